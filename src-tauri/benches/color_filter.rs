@@ -0,0 +1,53 @@
+// 按像素过滤颜色的耗时 cargo bench --bench color_filter
+use std::time::{Duration, Instant};
+use color_space::{Lab, Rgb};
+use image::{DynamicImage, Rgb as Pixel, RgbImage};
+
+#[allow(dead_code)]
+#[path = "../src/analyzer/palette.rs"]
+mod palette;
+
+const ITERATIONS: u32 = 20;
+
+// 由色块和渐变组成的截图大小的图片
+fn screenshot(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        match (x * 4 / width, y * 3 / height) {
+            (0, _) => Pixel([255, 255, 255]),
+            (1, 0) => Pixel([0, 120, 215]),
+            (2, _) => Pixel([(x % 256) as u8, (y % 256) as u8, 128]),
+            _ => Pixel([30, 30, 30]),
+        }
+    }))
+}
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed: Duration = start.elapsed() / ITERATIONS;
+    println!("{:<32} {:>10.3} ms", name, elapsed.as_secs_f64() * 1000.0);
+}
+
+fn main() {
+    let image = screenshot(1920, 1080);
+    let color = Lab::from(Rgb::new(0.0, 120.0, 215.0));
+    bench("downsample", || {
+        palette::downsample(&image);
+    });
+    let small = palette::downsample(&image);
+    let full = image.to_rgb8();
+    // 区间为[0,1]时不会提前结束
+    bench("pixel_coverage downsampled", || {
+        palette::pixel_coverage(&small, &color, 10.0, 0.0, 1.0);
+    });
+    bench("pixel_coverage full size", || {
+        palette::pixel_coverage(&full, &color, 10.0, 0.0, 1.0);
+    });
+    // 很快超过上限
+    bench("pixel_coverage early stop", || {
+        palette::pixel_coverage(&full, &color, 10.0, 0.0, 0.01);
+    });
+}
@@ -1 +1,4 @@
-pub mod ocr;
\ No newline at end of file
+pub mod ocr;
+pub mod palette;
+pub mod phash;
+pub mod thumbnail;
@@ -0,0 +1,217 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use color_space::{CompareCie2000, Lab, Rgb};
+use image::{DynamicImage, GenericImageView, RgbImage, Rgba};
+use rayon::prelude::*;
+
+// 每个通道量化后的级数 共8x8x8个颜色区间
+const LEVELS: usize = 8;
+const SHIFT: u8 = 5;
+// 每个区间占用的字节数 区间内像素的平均颜色RGB各1字节 比重2字节
+const ENTRY: usize = 5;
+// 每个任务处理的行数 处理完后检查覆盖比例是否还可能落在区间内
+const ROWS: usize = 8;
+// 逐像素计算前图片缩小到的最长边
+const DOWNSAMPLE_SIZE: u32 = 256;
+
+// 量化颜色直方图 只保存非空的区间
+// 每个区间记录其中像素的平均颜色和像素数占比 按区间序号排列
+pub fn make_palette<I: GenericImageView<Pixel = Rgba<u8>>>(image: &I) -> Vec<u8> {
+    // 每个区间的像素数和RGB之和
+    let mut bins = vec![[0u64; 4]; LEVELS * LEVELS * LEVELS];
+    for (_, _, p) in image.pixels() {
+        let index = ((p[0] >> SHIFT) as usize * LEVELS + (p[1] >> SHIFT) as usize) * LEVELS + (p[2] >> SHIFT) as usize;
+        let bin = &mut bins[index];
+        bin[0] += 1;
+        bin[1] += p[0] as u64;
+        bin[2] += p[1] as u64;
+        bin[3] += p[2] as u64;
+    }
+    let total = bins.iter().map(|bin| bin[0]).sum::<u64>().max(1) as f64;
+    let mut ret = vec![];
+    for bin in bins.iter().filter(|bin| bin[0] > 0) {
+        let count = bin[0];
+        let ratio = (count as f64 / total * u16::MAX as f64).round() as u16;
+        ret.push(((bin[1] + count / 2) / count) as u8);
+        ret.push(((bin[2] + count / 2) / count) as u8);
+        ret.push(((bin[3] + count / 2) / count) as u8);
+        ret.extend_from_slice(&ratio.to_le_bytes());
+    }
+    ret
+}
+
+// 与指定颜色的DeltaE不超过difference的像素占比 范围：[0,1]
+pub fn coverage(palette: &[u8], color: &Lab, difference: f64) -> f64 {
+    let mut ret = 0.0;
+    for entry in palette.chunks_exact(ENTRY) {
+        let c = Lab::from(Rgb::new(entry[0] as f64, entry[1] as f64, entry[2] as f64));
+        if color.compare_cie2000(&c) <= difference {
+            ret += u16::from_le_bytes([entry[3], entry[4]]) as f64 / u16::MAX as f64;
+        }
+    }
+    ret.min(1.0)
+}
+
+// 逐像素计算前缩小图片 不放大
+pub fn downsample(image: &DynamicImage) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let scale = (DOWNSAMPLE_SIZE as f64 / width.max(height).max(1) as f64).min(1.0);
+    let width = ((width as f64 * scale).round() as u32).max(1);
+    let height = ((height as f64 * scale).round() as u32).max(1);
+    image.thumbnail_exact(width, height).into_rgb8()
+}
+
+// 逐像素计算与指定颜色的DeltaE不超过difference的像素占比 用于还没有直方图的图片 图片应事先缩小
+// 在rayon的线程池中按行分块并行计算 覆盖比例确定落在[from,to]之外时提前结束并返回None
+pub fn pixel_coverage(image: &RgbImage, color: &Lab, difference: f64, from: f64, to: f64) -> Option<f64> {
+    pixel_coverage_inner(image, color, difference, from, to).0
+}
+
+// 同时返回实际计算过的像素数
+fn pixel_coverage_inner(image: &RgbImage, color: &Lab, difference: f64, from: f64, to: f64) -> (Option<f64>, u64) {
+    let (width, height) = image.dimensions();
+    let total = width as u64 * height as u64;
+    if total == 0 {
+        return (None, 0);
+    }
+    let matched = AtomicU64::new(0);
+    let remaining = AtomicU64::new(total);
+    let stop = AtomicBool::new(false);
+    image.as_raw().par_chunks(width as usize * 3 * ROWS).for_each(|rows| {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        // 截图中相邻像素的颜色大多相同 复用上一个像素的结果
+        let mut last: Option<(&[u8], bool)> = None;
+        let mut count = 0;
+        for p in rows.chunks_exact(3) {
+            let hit = match last {
+                Some((q, hit)) if q == p => hit,
+                _ => {
+                    let c = Lab::from(Rgb::new(p[0] as f64, p[1] as f64, p[2] as f64));
+                    let hit = color.compare_cie2000(&c) <= difference;
+                    last = Some((p, hit));
+                    hit
+                }
+            };
+            if hit {
+                count += 1;
+            }
+        }
+        let matched = matched.fetch_add(count, Ordering::Relaxed) + count;
+        let pixels = (rows.len() / 3) as u64;
+        let remaining = remaining.fetch_sub(pixels, Ordering::Relaxed) - pixels;
+        // 已经超过上限 或者剩余像素全部命中也达不到下限
+        if matched as f64 / total as f64 > to || (matched + remaining) as f64 / (total as f64) < from {
+            stop.store(true, Ordering::Relaxed);
+        }
+    });
+    let scanned = total - remaining.load(Ordering::Relaxed);
+    if stop.load(Ordering::Relaxed) {
+        return (None, scanned);
+    }
+    (Some(matched.load(Ordering::Relaxed) as f64 / total as f64), scanned)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb as Pixel;
+    use super::*;
+
+    // 原图逐像素计算的覆盖比例 作为缩小后计算结果的参照
+    fn oracle(image: &RgbImage, color: &Lab, difference: f64) -> f64 {
+        let mut count = 0;
+        for p in image.pixels() {
+            let c = Lab::from(Rgb::new(p[0] as f64, p[1] as f64, p[2] as f64));
+            if color.compare_cie2000(&c) <= difference {
+                count += 1;
+            }
+        }
+        count as f64 / (image.width() as u64 * image.height() as u64) as f64
+    }
+
+    // 由色块组成的图片 类似截图
+    fn blocks(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            match (x * 4 / width, y * 3 / height) {
+                (0, _) => Pixel([255, 0, 0]),
+                (1, 0) => Pixel([0, 0, 255]),
+                (1, _) => Pixel([255, 255, 255]),
+                (2, _) => Pixel([(x % 256) as u8, 128, 0]),
+                _ => Pixel([30, 30, 30]),
+            }
+        })
+    }
+
+    fn lab(r: f64, g: f64, b: f64) -> Lab {
+        Lab::from(Rgb::new(r, g, b))
+    }
+
+    #[test]
+    fn downsampled_coverage_matches_oracle() {
+        for (width, height) in [(1920, 1080), (1080, 1920), (300, 200), (100, 100)] {
+            let image = blocks(width, height);
+            let small = downsample(&DynamicImage::ImageRgb8(image.clone()));
+            assert!(small.width().max(small.height()) <= DOWNSAMPLE_SIZE);
+            for (color, difference) in [(lab(255.0, 0.0, 0.0), 10.0), (lab(0.0, 0.0, 255.0), 5.0), (lab(255.0, 255.0, 255.0), 1.0),
+                (lab(128.0, 128.0, 0.0), 20.0), (lab(0.0, 255.0, 0.0), 10.0)] {
+                let expected = oracle(&image, &color, difference);
+                let actual = pixel_coverage(&small, &color, difference, 0.0, 1.0).unwrap();
+                assert!((actual - expected).abs() <= 0.02, "{}x{} {:?} {} {}", width, height, color, actual, expected);
+            }
+        }
+    }
+
+    // 颜色直方图用区间的平均颜色近似区间内的像素 与逐像素计算的结果允许有一定偏差
+    // 色块图片中渐变的区域偏差最大
+    const PALETTE_TOLERANCE: f64 = 0.1;
+
+    #[test]
+    fn palette_coverage_close_to_pixel_coverage() {
+        let mut images = vec![];
+        for name in ["icon.png", "128x128.png", "Square310x310Logo.png"] {
+            let path = format!("{}/icons/{}", env!("CARGO_MANIFEST_DIR"), name);
+            images.push((name.to_string(), image::open(path).unwrap()));
+        }
+        for (width, height) in [(1920, 1080), (300, 200)] {
+            images.push((format!("{}x{}", width, height), DynamicImage::ImageRgb8(blocks(width, height))));
+        }
+        for (name, image) in images {
+            // 与搜索时一致 直方图取自原图 逐像素计算使用缩小后的图片
+            let palette = make_palette(&image.to_rgba8());
+            let small = downsample(&image);
+            for r in (0..=255).step_by(85) {
+                for g in (0..=255).step_by(85) {
+                    for b in (0..=255).step_by(85) {
+                        for difference in [5.0, 10.0, 20.0] {
+                            let color = lab(r as f64, g as f64, b as f64);
+                            let approximate = coverage(&palette, &color, difference);
+                            let exact = pixel_coverage(&small, &color, difference, 0.0, 1.0).unwrap();
+                            assert!((approximate - exact).abs() <= PALETTE_TOLERANCE,
+                                "{} {:?} {} {} {}", name, color, difference, approximate, exact);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stop_early_on_both_bounds() {
+        let image = RgbImage::from_pixel(1000, 2000, Pixel([255, 0, 0]));
+        let total = 1000 * 2000;
+        // 单线程时按顺序处理各块 提前结束时计算过的像素数不受CPU核数影响
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        // 超过上限
+        let (ret, scanned) = pool.install(|| pixel_coverage_inner(&image, &lab(255.0, 0.0, 0.0), 10.0, 0.0, 0.1));
+        assert!(ret.is_none());
+        assert!(scanned < total / 2, "{}", scanned);
+        // 达不到下限
+        let (ret, scanned) = pool.install(|| pixel_coverage_inner(&image, &lab(0.0, 0.0, 255.0), 10.0, 0.9, 1.0));
+        assert!(ret.is_none());
+        assert!(scanned < total / 2, "{}", scanned);
+        // 恰好在区间的边界上时全部计算
+        assert_eq!(pixel_coverage_inner(&image, &lab(255.0, 0.0, 0.0), 10.0, 1.0, 1.0), (Some(1.0), total));
+        assert_eq!(pixel_coverage_inner(&image, &lab(0.0, 0.0, 255.0), 10.0, 0.0, 0.0), (Some(0.0), total));
+        assert_eq!(pixel_coverage(&RgbImage::new(0, 0), &lab(0.0, 0.0, 0.0), 10.0, 0.0, 1.0), None);
+    }
+}
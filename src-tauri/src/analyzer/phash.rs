@@ -0,0 +1,28 @@
+use image::{GenericImageView, Pixel, Rgba};
+use image::imageops::{resize, FilterType};
+
+const HASH_WIDTH: u32 = 9;
+const HASH_HEIGHT: u32 = 8;
+
+// 差异哈希(dHash) 缩放到9x8的灰度图后比较每行相邻像素的亮度 共64位
+// 同一窗口的截图即使鼠标或时钟不同 哈希值也只相差少量位
+pub fn dhash<I: GenericImageView<Pixel = Rgba<u8>>>(image: &I) -> i64 {
+    let small = resize(image, HASH_WIDTH, HASH_HEIGHT, FilterType::Triangle);
+    let mut hash: u64 = 0;
+    for y in 0..HASH_HEIGHT {
+        for x in 0..HASH_WIDTH - 1 {
+            let left = small.get_pixel(x, y).to_luma()[0];
+            let right = small.get_pixel(x + 1, y).to_luma()[0];
+            hash <<= 1;
+            if left < right {
+                hash |= 1;
+            }
+        }
+    }
+    hash as i64
+}
+
+// 两个哈希之间的汉明距离 范围：[0,64]
+pub fn distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
@@ -0,0 +1,19 @@
+use std::io::Cursor;
+use anyhow::Result;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, Rgba};
+use image::imageops::thumbnail;
+
+// 缩略图最长边的像素数
+const THUMBNAIL_SIZE: u32 = 256;
+
+// 生成等比例缩放的PNG缩略图 原图小于缩略图尺寸时不放大
+pub fn make_thumbnail<I: GenericImageView<Pixel = Rgba<u8>>>(image: &I) -> Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let scale = (THUMBNAIL_SIZE as f64 / width.max(height).max(1) as f64).min(1.0);
+    let width = ((width as f64 * scale).round() as u32).max(1);
+    let height = ((height as f64 * scale).round() as u32).max(1);
+    let small = thumbnail(image, width, height);
+    let mut data = Cursor::new(vec![]);
+    DynamicImage::ImageRgba8(small).write_to(&mut data, ImageOutputFormat::Png)?;
+    Ok(data.into_inner())
+}
@@ -1,17 +1,26 @@
 use std::ops::Not;
 use anyhow::Result;
-use log::error;
+use log::{error, info};
 use once_cell::sync::Lazy;
 use serde::{Serialize, Deserialize};
 use tauri::{AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem, Window, Wry};
+use crate::client::{backup, crypto};
+use crate::client::query::Query;
 use crate::client::sqlite::client;
-use crate::{clipboard, settings};
+use crate::{clipboard, regular, settings};
 use crate::analyzer::ocr;
-use crate::model::Image;
+use crate::model::{Backup, CleanCandidate, Collection, EncryptionStatus, Image, ImageData, ImageOccurrence, ImagePage, ImportResult, MaintenanceReport, Statistics, Tag};
 use crate::settings::Settings;
 
+pub mod image_archive;
+pub mod image_clean;
+pub mod image_collection;
+pub mod image_edit;
 pub mod image_insert;
 pub mod image_search;
+pub mod image_statistics;
+pub mod image_tag;
+pub mod image_trash;
 
 fn conv_result<T: Serialize, E: ToString>(r: Result<T, E>) -> Result<T, String> {
     match r {
@@ -36,34 +45,122 @@ pub struct ColorFilter {
     pub green: u8,
     pub blue: u8,
     // 覆盖比例 范围：[0,1]
+    // 有颜色直方图的图片按直方图计算 以区间内的平均颜色近似其中的像素
+    // 与没有直方图时逐像素计算的比例可能相差约0.1 在区间边界附近的图片两种方式的结果可能不同
     pub cover_ratio_from: f64,
     pub cover_ratio_to: f64,
     // 可接受的DeltaE 范围：[0,100]
     pub difference: f64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimilarTo {
+    pub id: i64,
+    // 可接受的感知哈希汉明距离 范围：[0,64]
+    pub max_distance: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TagFilterMode {
+    // 包含任意一个标签
+    ANY,
+    // 包含全部标签
+    ALL,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagFilter {
+    pub id: Vec<i64>,
+    pub mode: TagFilterMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Orientation {
+    // 高大于宽
+    Portrait,
+    // 宽大于高
+    Landscape,
+    // 宽高相等
+    Square,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ImageSortField {
+    Ctime,
+    Mtime,
+    Size,
+    // 再次复制的次数
+    UseCount,
+    // 最近一次再次复制的时间
+    LastUsed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SortDirection {
+    ASC,
+    DESC,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageSort {
+    pub field: ImageSortField,
+    pub direction: SortDirection,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetImageRequest {
-    // 上一次返回图片中最小的mtime
+    // 上一次返回图片中最小的mtime 相同mtime的图片会被跳过 只为兼容保留 翻页使用cursor
     pub mtime: Option<i64>,
+    // 上一页返回的游标
+    pub cursor: Option<String>,
     // 返回图片的最大数量
     pub limit: Option<i64>,
     pub id: Option<Vec<i64>>,
+    // 搜索OCR文本、标题和备注 多个搜索词之间为或的关系 支持FTS5的短语、括号以及AND/OR/NOT语法 只做子串匹配 不支持前缀查询
     pub text: Option<Vec<String>>,
     pub date_range_from: Option<i64>,
     pub date_range_to: Option<i64>,
+    // 宽、高和文件字节数的范围 包含边界
+    pub width_from: Option<i32>,
+    pub width_to: Option<i32>,
+    pub height_from: Option<i32>,
+    pub height_to: Option<i32>,
+    pub size_from: Option<i64>,
+    pub size_to: Option<i64>,
+    // 宽高比的范围 宽除以高 包含边界
+    pub aspect_ratio_from: Option<f64>,
+    pub aspect_ratio_to: Option<f64>,
+    pub orientation: Option<Orientation>,
     pub color_filter: Option<ColorFilter>,
+    // 同时满足多个颜色条件 与color_filter合并 没有指定其他排序时按覆盖比例之和排序
+    pub color_filters: Option<Vec<ColorFilter>>,
+    // 查找与指定图片相似的图片 按相似程度排序
+    pub similar_to: Option<SimilarTo>,
+    // 只返回缩略图 不返回原图
+    pub thumbnail: Option<bool>,
+    pub tags: Option<TagFilter>,
+    // 只返回置顶或者非置顶的图片
+    pub pinned: Option<bool>,
+    // 置顶的图片排在最前
+    pub pinned_first: Option<bool>,
+    // 只返回图集中的图片 按图集中的顺序排列
+    pub collection_id: Option<i64>,
+    // 只返回回收站中的图片 默认不返回回收站中的图片
+    pub trash: Option<bool>,
+    // 指定排序方式 优先于相似程度、相关度和图集顺序 默认按mtime倒序
+    pub sort: Option<ImageSort>,
 }
 
 #[tauri::command(rename_all = "snake_case")]
-async fn get_image(request: GetImageRequest) -> Result<Vec<Image>, String> {
+async fn get_image(request: GetImageRequest) -> Result<ImagePage, String> {
     match image_search::get_image(request).await {
-        Ok(img) => {
+        Ok(page) => {
             let mut resp = vec![];
-            for img in img {
+            for img in page.images {
                 resp.push(Image {
                     id: img.id,
-                    image: img.image.to_base64(),
+                    image: img.image.map(|image| image.to_base64()),
+                    thumbnail: img.thumbnail.map(|thumbnail| thumbnail.to_base64()),
                     ocr: img.ocr,
                     size: img.size,
                     width: img.width,
@@ -71,14 +168,40 @@ async fn get_image(request: GetImageRequest) -> Result<Vec<Image>, String> {
                     ctime: img.ctime,
                     mtime: img.mtime,
                     sum: img.sum,
+                    occurrence: img.occurrence,
+                    distance: img.distance,
+                    snippet: img.snippet,
+                    pinned: img.pinned,
+                    tags: img.tags,
+                    title: img.title,
+                    note: img.note,
+                    deleted_at: img.deleted_at,
+                    use_count: img.use_count,
+                    last_used_at: img.last_used_at,
+                    color_coverage: img.color_coverage,
                 });
             }
-            Ok(resp)
+            Ok(ImagePage { images: resp, cursor: page.cursor })
         }
         Err(err) => Err(err.to_string()),
     }
 }
 
+// 通过ID获取原图
+#[tauri::command(rename_all = "snake_case")]
+async fn get_image_data(image_id: i64) -> Result<ImageData, String> {
+    match image_search::get_image_data(image_id) {
+        Ok(image) => Ok(image.to_base64()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// 获取图片被复制或上传的记录
+#[tauri::command(rename_all = "snake_case")]
+async fn get_image_occurrence(image_id: i64) -> Result<Vec<ImageOccurrence>, String> {
+    conv_result(image_search::get_image_occurrence(image_id))
+}
+
 #[tauri::command(rename_all = "snake_case")]
 async fn re_copy(image_id: i32) -> Result<(), String> {
     conv_result(clipboard::re_copy(image_id))
@@ -86,17 +209,235 @@ async fn re_copy(image_id: i32) -> Result<(), String> {
 
 // 通过ID删除图片
 #[tauri::command(rename_all = "snake_case")]
-async fn delete_image(image_id: Vec<i32>) -> Result<(), String> {
+async fn delete_image(image_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_trash::trash_image(&image_id))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn restore_image(image_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_trash::restore_image(&image_id))
+}
+
+// 彻底删除回收站中的图片 不指定ID时清空回收站 返回删除的数量
+#[tauri::command(rename_all = "snake_case")]
+async fn purge_trash(image_id: Option<Vec<i64>>) -> Result<usize, String> {
+    conv_result(image_trash::purge_trash(&image_id))
+}
+
+// 按当前的保留策略列出将被删除的图片 不会删除
+#[tauri::command(rename_all = "snake_case")]
+async fn clean_dry_run() -> Result<Vec<CleanCandidate>, String> {
+    let policy = image_clean::RetentionPolicy::from_settings(&settings::get_settings());
+    conv_result(image_clean::plan(&policy))
+}
+
+// 置顶或取消置顶图片 支持批量
+#[tauri::command(rename_all = "snake_case")]
+async fn pin_image(image_id: Vec<i64>, pinned: bool) -> Result<(), String> {
     let inner = || -> Result<()> {
-        let client = client();
-        let image_id: Vec<String> = image_id.iter().map(|v| v.to_string()).collect();
-        let sql = format!(r#"DELETE FROM image WHERE id IN ({})"#, image_id.join(", "));
-        client.execute(sql.as_str(), ())?;
+        let client = client()?;
+        let mut query = Query::default();
+        query.push_bind("UPDATE image SET pinned = ? WHERE id IN", [pinned]).push_list(image_id.iter().cloned());
+        client.execute(query.sql(), query.params())?;
         Ok(())
     };
     conv_result(inner())
 }
 
+// 标题或备注为空时清除
+#[tauri::command(rename_all = "snake_case")]
+async fn set_image_title(image_id: i64, title: Option<String>) -> Result<(), String> {
+    conv_result(image_edit::set_title(image_id, title))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn set_image_note(image_id: i64, note: Option<String>) -> Result<(), String> {
+    conv_result(image_edit::set_note(image_id, note))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+fn encryption_status() -> EncryptionStatus {
+    EncryptionStatus {
+        enabled: crypto::is_enabled(),
+        unlocked: !crypto::is_locked(),
+    }
+}
+
+// 在阻塞线程池中执行同步的耗时操作 不占用异步运行时的线程
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f).await?
+}
+
+// 删除开启加密或修改口令前的备份 再使用当前的密钥重新备份
+fn replace_backups() -> Result<()> {
+    let count = backup::remove_all()?;
+    info!("removed {} backups created before encryption change", count);
+    backup::create()?;
+    Ok(())
+}
+
+// 开启加密 加密已有的图片、缩略图、OCR文本、标题和备注
+// 期间暂停后台任务和剪切板写入 避免备份到加密了一半的数据 完成后替换已有的明文备份
+#[tauri::command(rename_all = "snake_case")]
+async fn enable_encryption(passphrase: String) -> Result<(), String> {
+    let _pause = regular::PAUSE.write().await;
+    conv_result(blocking(move || {
+        crypto::enable(&passphrase)?;
+        replace_backups()
+    }).await)
+}
+
+// 启动后输入口令解锁加密的历史 并保存解锁前复制的图片
+#[tauri::command(rename_all = "snake_case")]
+async fn unlock(passphrase: String) -> Result<(), String> {
+    conv_result(crypto::unlock(&passphrase))?;
+    tokio::task::spawn_blocking(image_insert::save_pending);
+    Ok(())
+}
+
+// 修改口令并使用新的密钥重新加密全部数据
+#[tauri::command(rename_all = "snake_case")]
+async fn change_passphrase(old_passphrase: String, new_passphrase: String) -> Result<(), String> {
+    let _pause = regular::PAUSE.write().await;
+    conv_result(blocking(move || {
+        crypto::change_passphrase(&old_passphrase, &new_passphrase)?;
+        // 旧的备份可以用旧口令解锁
+        replace_backups()
+    }).await)
+}
+
+// 把满足条件的图片导出为zip 返回导出的图片数量
+#[tauri::command(rename_all = "snake_case")]
+async fn export_image(request: GetImageRequest, path: String) -> Result<usize, String> {
+    conv_result(image_archive::export_archive(request, &path).await)
+}
+
+// 导入export_image导出的zip
+#[tauri::command(rename_all = "snake_case")]
+async fn import_image(path: String) -> Result<ImportResult, String> {
+    conv_result(blocking(move || image_archive::import_archive(&path)).await)
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn create_backup() -> Result<Backup, String> {
+    let _pause = regular::PAUSE.read().await;
+    conv_result(blocking(backup::create).await)
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn get_backup() -> Result<Vec<Backup>, String> {
+    conv_result(backup::list())
+}
+
+// 恢复备份 期间暂停后台任务和剪切板写入
+#[tauri::command(rename_all = "snake_case")]
+async fn restore_backup(name: String) -> Result<(), String> {
+    let _pause = regular::PAUSE.write().await;
+    conv_result(blocking(move || {
+        // 恢复前先备份当前数据库 当前数据库损坏时无法备份 不影响恢复
+        if let Err(err) = backup::create() {
+            error!("backup before restore with error: {}", err.to_string());
+        }
+        backup::restore(&name)
+    }).await)
+}
+
+// 存储和使用情况的统计
+#[tauri::command(rename_all = "snake_case")]
+async fn get_statistics() -> Result<Statistics, String> {
+    conv_result(image_statistics::get_statistics())
+}
+
+// 立即执行一次数据库维护
+#[tauri::command(rename_all = "snake_case")]
+async fn run_maintenance() -> Result<MaintenanceReport, String> {
+    conv_result(regular::maintenance::run().await)
+}
+
+// 最近一次数据库维护的结果 启动后还没有执行时为空
+#[tauri::command(rename_all = "snake_case")]
+async fn get_maintenance_report() -> Result<Option<MaintenanceReport>, String> {
+    Ok(regular::maintenance::get_report())
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn create_tag(name: String) -> Result<Tag, String> {
+    conv_result(image_tag::create_tag(&name))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn rename_tag(tag_id: i64, name: String) -> Result<(), String> {
+    conv_result(image_tag::rename_tag(tag_id, &name))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn delete_tag(tag_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_tag::delete_tag(&tag_id))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn get_tag() -> Result<Vec<Tag>, String> {
+    conv_result(image_tag::get_tag())
+}
+
+// 给图片添加标签 支持批量
+#[tauri::command(rename_all = "snake_case")]
+async fn assign_tag(image_id: Vec<i64>, tag_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_tag::assign_tag(&image_id, &tag_id))
+}
+
+// 移除图片的标签 支持批量
+#[tauri::command(rename_all = "snake_case")]
+async fn unassign_tag(image_id: Vec<i64>, tag_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_tag::unassign_tag(&image_id, &tag_id))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn create_collection(name: String) -> Result<Collection, String> {
+    conv_result(image_collection::create_collection(&name))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn rename_collection(collection_id: i64, name: String) -> Result<(), String> {
+    conv_result(image_collection::rename_collection(collection_id, &name))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn delete_collection(collection_id: i64) -> Result<(), String> {
+    conv_result(image_collection::delete_collection(collection_id))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn get_collection() -> Result<Vec<Collection>, String> {
+    conv_result(image_collection::get_collection())
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn reorder_collection(collection_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_collection::reorder_collection(&collection_id))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn add_collection_image(collection_id: i64, image_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_collection::add_collection_image(collection_id, &image_id))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn remove_collection_image(collection_id: i64, image_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_collection::remove_collection_image(collection_id, &image_id))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn reorder_collection_image(collection_id: i64, image_id: Vec<i64>) -> Result<(), String> {
+    conv_result(image_collection::reorder_collection_image(collection_id, &image_id))
+}
+
+// 把图集中的原图导出到指定目录 返回导出的图片数量
+#[tauri::command(rename_all = "snake_case")]
+async fn export_collection(collection_id: i64, directory: String) -> Result<usize, String> {
+    conv_result(image_collection::export_collection(collection_id, &directory))
+}
+
 // 上传图片
 #[tauri::command(rename_all = "snake_case")]
 async fn upload_image(image_path: Vec<String>) -> Result<(), String> {
@@ -195,8 +536,43 @@ pub async fn run() -> Result<()> {
             get_settings,
             set_settings,
             get_image,
+            get_image_data,
+            get_image_occurrence,
             re_copy,
             delete_image,
+            restore_image,
+            purge_trash,
+            clean_dry_run,
+            pin_image,
+            set_image_title,
+            set_image_note,
+            create_backup,
+            get_backup,
+            restore_backup,
+            run_maintenance,
+            get_maintenance_report,
+            get_statistics,
+            export_image,
+            import_image,
+            encryption_status,
+            enable_encryption,
+            unlock,
+            change_passphrase,
+            create_tag,
+            rename_tag,
+            delete_tag,
+            get_tag,
+            assign_tag,
+            unassign_tag,
+            create_collection,
+            rename_collection,
+            delete_collection,
+            get_collection,
+            reorder_collection,
+            add_collection_image,
+            remove_collection_image,
+            reorder_collection_image,
+            export_collection,
             close_window,
             upload_image,
             ocr_status,
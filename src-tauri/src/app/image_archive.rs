@@ -0,0 +1,263 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use anyhow::{bail, Result};
+use rusqlite::{named_params, OptionalExtension, Transaction, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::write::FileOptions;
+use crate::app::{image_search, GetImageRequest};
+use crate::app::image_insert::ImageAnalysis;
+use crate::client::{blob, crypto, fts};
+use crate::client::sqlite::client;
+use crate::common::pixel_sum;
+use crate::model::{Image, ImageOccurrence, ImportResult, OCR};
+
+const MANIFEST: &str = "manifest.json";
+const MANIFEST_VERSION: i64 = 1;
+// 导入时没有出现记录的图片使用的来源
+const SOURCE_IMPORT: &str = "import";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestImage {
+    id: i64,
+    sum: String,
+    // 压缩包中原图的路径
+    file: String,
+    size: i64,
+    width: i32,
+    height: i32,
+    ctime: i64,
+    mtime: i64,
+    ocr: Option<OCR>,
+    title: Option<String>,
+    note: Option<String>,
+    pinned: bool,
+    tags: Vec<String>,
+    occurrence: Vec<ImageOccurrence>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Manifest {
+    version: i64,
+    ctime: i64,
+    images: Vec<ManifestImage>,
+}
+
+// 导出时每页读取的图片数量
+const EXPORT_PAGE: i64 = 64;
+
+type Writer = ZipWriter<File>;
+
+// 逐个读取原图写入压缩包 返回对应的元数据
+fn write_images(mut writer: Writer, images: Vec<Image>) -> Result<(Writer, Vec<ManifestImage>)> {
+    // PNG已经压缩过 直接保存
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut ret = vec![];
+    for image in images {
+        let file = format!("images/{}.png", image.sum);
+        writer.start_file(file.as_str(), stored)?;
+        writer.write_all(blob::get(&image.sum)?.as_slice())?;
+        ret.push(ManifestImage {
+            id: image.id,
+            sum: image.sum,
+            file,
+            size: image.size,
+            width: image.width,
+            height: image.height,
+            ctime: image.ctime,
+            mtime: image.mtime,
+            ocr: image.ocr,
+            title: image.title,
+            note: image.note,
+            pinned: image.pinned,
+            tags: image.tags.into_iter().map(|tag| tag.name).collect(),
+            occurrence: image_search::get_image_occurrence(image.id)?,
+        });
+    }
+    Ok((writer, ret))
+}
+
+// 把满足条件的图片导出为zip 包含原图和描述元数据的manifest.json 返回导出的图片数量
+// 按游标分页读取元数据 原图在写入时再逐个读取 没有指定数量时导出全部满足条件的图片
+pub async fn export_archive(request: GetImageRequest, path: &str) -> Result<usize> {
+    let mut request = request;
+    let mut remaining = request.limit;
+    request.thumbnail = None;
+    let mut writer = ZipWriter::new(File::create(path)?);
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        ctime: chrono::Local::now().timestamp_millis(),
+        images: vec![],
+    };
+    loop {
+        request.limit = Some(remaining.map_or(EXPORT_PAGE, |remaining| remaining.min(EXPORT_PAGE)));
+        let page = image_search::get_image_metadata(request.clone()).await?;
+        if let Some(remaining) = &mut remaining {
+            *remaining -= page.images.len() as i64;
+        }
+        let images = page.images;
+        let (w, images) = tokio::task::spawn_blocking(move || write_images(writer, images)).await??;
+        writer = w;
+        manifest.images.extend(images);
+        match page.cursor {
+            Some(cursor) if remaining.map_or(true, |remaining| remaining > 0) => request.cursor = Some(cursor),
+            _ => break,
+        }
+    }
+    tokio::task::spawn_blocking(move || -> Result<usize> {
+        writer.start_file(MANIFEST, FileOptions::default())?;
+        writer.write_all(serde_json::to_string(&manifest)?.as_bytes())?;
+        writer.finish()?;
+        Ok(manifest.images.len())
+    }).await?
+}
+
+fn get_tag_id(tx: &Transaction, name: &str) -> Result<i64> {
+    let now = chrono::Local::now().timestamp_millis();
+    tx.execute("INSERT OR IGNORE INTO tag (name, ctime) VALUES (?1, ?2)", (name.trim(), &now))?;
+    Ok(tx.query_row("SELECT id FROM tag WHERE name = ?1", (name.trim(),), |row| row.get(0))?)
+}
+
+// 导入一张图片 已经存在相同sum的图片时合并时间和出现记录 返回是否为新图片
+fn import_image(image: &ManifestImage, data: &[u8]) -> Result<bool> {
+    let pixels = image::load_from_memory(data)?.into_rgba8();
+    let sum = pixel_sum(pixels.width(), pixels.height(), pixels.as_raw());
+    if sum != image.sum {
+        bail!("image {} does not match its sum", image.file);
+    }
+    // 与保存剪切板图片时相同 计算感知哈希、缩略图和颜色直方图
+    let analysis = ImageAnalysis::new(sum, &pixels)?;
+    let sum = &analysis.sum;
+    let thumbnail = crypto::seal(analysis.thumbnail.as_slice())?;
+    let ocr = match &image.ocr {
+        Some(ocr) => Some(serde_json::to_string(ocr)?),
+        None => None,
+    };
+    let ocr = crypto::seal_text(ocr.as_deref())?;
+    let title = crypto::seal_text(image.title.as_deref())?;
+    let note = crypto::seal_text(image.note.as_deref())?;
+
+    let mut client = client()?;
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let id: Option<i64> = tx.query_row(
+        "SELECT id FROM image WHERE sum = :sum LIMIT 1",
+        named_params! { ":sum": sum },
+        |row| row.get(0),
+    ).optional()?;
+    let inserted = id.is_none();
+    let id = match id {
+        Some(id) => {
+            // 保留最早的创建时间和最近的使用时间 已有的OCR、标题和备注优先 在回收站中的图片会被恢复
+            tx.execute(r#"UPDATE image SET ctime = MIN(ctime, ?2), mtime = MAX(mtime, ?3),
+                       ocr = IFNULL(ocr, ?4), title = IFNULL(title, ?5), note = IFNULL(note, ?6),
+                       pinned = MAX(pinned, ?7), deleted_at = NULL WHERE id = ?1"#,
+                       (&id, &image.ctime, &image.mtime, &ocr, &title, &note, &image.pinned))?;
+            id
+        }
+        None => {
+            blob::put(sum, data)?;
+            tx.execute(r#"INSERT INTO image (size, width, height, ctime, mtime, sum, phash, thumbnail, palette, ocr, title, note, pinned)
+                       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)"#,
+                       (&(data.len() as i64), &(pixels.width() as i32), &(pixels.height() as i32), &image.ctime, &image.mtime,
+                        sum, &analysis.phash, &thumbnail, &analysis.palette, &ocr, &title, &note, &image.pinned))?;
+            tx.last_insert_rowid()
+        }
+    };
+    // 出现记录按时间和来源去重
+    let mut occurrence = image.occurrence.clone();
+    if occurrence.is_empty() {
+        occurrence.push(ImageOccurrence { ctime: image.ctime, source: Some(SOURCE_IMPORT.to_string()) });
+    }
+    for o in &occurrence {
+        tx.execute(r#"INSERT INTO image_occurrence (image_id, ctime, source)
+                   SELECT ?1, ?2, ?3 WHERE NOT EXISTS
+                   (SELECT 1 FROM image_occurrence WHERE image_id = ?1 AND ctime = ?2 AND source IS ?3)"#,
+                   (&id, &o.ctime, &o.source))?;
+    }
+    for name in image.tags.iter().filter(|name| !name.trim().is_empty()) {
+        let tag_id = get_tag_id(&tx, name)?;
+        tx.execute("INSERT OR IGNORE INTO image_tag (image_id, tag_id) VALUES (?1, ?2)", (&id, &tag_id))?;
+    }
+    fts::update(&tx, id)?;
+    tx.commit()?;
+    Ok(inserted)
+}
+
+// 导入export_archive导出的zip 按sum去重 保留原本的时间
+pub fn import_archive(path: &str) -> Result<ImportResult> {
+    crypto::check_unlocked()?;
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let manifest: Manifest = {
+        let mut text = String::new();
+        archive.by_name(MANIFEST)?.read_to_string(&mut text)?;
+        serde_json::from_str(text.as_str())?
+    };
+    if manifest.version > MANIFEST_VERSION {
+        bail!("archive version {} is not supported", manifest.version);
+    }
+    let mut ret = ImportResult { inserted: 0, merged: 0 };
+    for image in &manifest.images {
+        let mut data = vec![];
+        archive.by_name(image.file.as_str())?.read_to_end(&mut data)?;
+        if import_image(image, data.as_slice())? {
+            ret.inserted += 1;
+        } else {
+            ret.merged += 1;
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use image::{ImageOutputFormat, Rgba, RgbaImage};
+    use serde_json::json;
+    use crate::app::image_insert::{insert_image, SOURCE_UPLOAD};
+    use crate::app::image_trash;
+    use crate::client::sqlite::testing;
+    use crate::common::get_root;
+    use super::*;
+
+    // 超过一页的不同颜色的图片 最后一张在回收站中
+    fn seed(count: u32) {
+        for i in 0..count {
+            let pixels = RgbaImage::from_pixel(2, 2, Rgba([i as u8, (i / 256) as u8, 7, 255]));
+            let mut data = Cursor::new(vec![]);
+            pixels.write_to(&mut data, ImageOutputFormat::Png).unwrap();
+            let analysis = ImageAnalysis::new(pixel_sum(2, 2, pixels.as_raw()), &pixels).unwrap();
+            insert_image(&data.into_inner(), &2, &2, &analysis, SOURCE_UPLOAD).unwrap();
+        }
+        let id: i64 = client().unwrap().query_row("SELECT MAX(id) FROM image", (), |row| row.get(0)).unwrap();
+        image_trash::trash_image(&vec![id]).unwrap();
+    }
+
+    fn export(runtime: &tokio::runtime::Runtime, request: serde_json::Value) -> usize {
+        let path = get_root().join("export.zip");
+        runtime.block_on(export_archive(serde_json::from_value(request).unwrap(), path.to_str().unwrap())).unwrap()
+    }
+
+    #[test]
+    fn export_and_import() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let count = EXPORT_PAGE as u32 * 2 + 3;
+        {
+            let _lock = testing::reset();
+            seed(count);
+            assert_eq!(export(&runtime, json!({"limit": 5})), 5);
+            assert_eq!(export(&runtime, json!({"limit": EXPORT_PAGE})), EXPORT_PAGE as usize);
+            // 回收站中的图片不导出
+            assert_eq!(export(&runtime, json!({})), count as usize - 1);
+        }
+        let _lock = testing::reset();
+        let path = get_root().join("export.zip");
+        let result = import_archive(path.to_str().unwrap()).unwrap();
+        assert_eq!((result.inserted, result.merged), (count as usize - 1, 0));
+        // 导入的图片同样计算了颜色直方图和缩略图
+        let missing: i64 = client().unwrap().query_row(
+            "SELECT COUNT(*) FROM image WHERE palette IS NULL OR thumbnail IS NULL OR phash IS NULL", (), |row| row.get(0)).unwrap();
+        assert_eq!(missing, 0);
+        let result = import_archive(path.to_str().unwrap()).unwrap();
+        assert_eq!((result.inserted, result.merged), (0, count as usize - 1));
+    }
+}
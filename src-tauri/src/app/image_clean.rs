@@ -0,0 +1,245 @@
+use std::collections::{HashMap, HashSet};
+use anyhow::Result;
+use log::warn;
+use rusqlite::named_params;
+use crate::app::image_trash;
+use crate::client::blob;
+use crate::client::sqlite::client;
+use crate::model::{CleanCandidate, CleanReason};
+use crate::settings::{CleanOrder, DatabaseLimitType, Settings};
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+const MB: i64 = 1024 * 1024;
+// 每个事务删除的图片数量
+const BATCH: usize = 500;
+
+// 保留策略 为0时不限制
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    pub max_age_days: i64,
+    pub max_count: i64,
+    pub max_mb: i64,
+    pub daily_limit: i64,
+    pub order: CleanOrder,
+}
+
+impl RetentionPolicy {
+    pub fn from_settings(settings: &Settings) -> RetentionPolicy {
+        // 没有设置新的上限时沿用旧版本的存储上限
+        let (legacy_mb, legacy_count) = match (&settings.database_limit_type, settings.database_limit) {
+            (Some(DatabaseLimitType::NUM), Some(limit)) => (0, limit),
+            (_, limit) => (limit.unwrap_or(1024), 0),
+        };
+        RetentionPolicy {
+            max_age_days: settings.retention_max_age_days.unwrap_or(0),
+            max_count: settings.retention_max_count.unwrap_or(legacy_count),
+            max_mb: settings.retention_max_mb.unwrap_or(legacy_mb),
+            daily_limit: settings.retention_daily_limit.unwrap_or(0),
+            order: settings.clean_order.unwrap_or(CleanOrder::Oldest),
+        }
+    }
+}
+
+struct Row {
+    id: i64,
+    size: i64,
+    ctime: i64,
+    mtime: i64,
+    // 最后使用时间 没有使用过时为修改时间
+    used: i64,
+    pinned: bool,
+    // 保存日期 按本地时间
+    day: String,
+    // 彻底删除后释放的字节数 包括图片文件和缩略图
+    bytes: i64,
+}
+
+// 数据库实际使用的字节数
+// 删除记录不会缩小数据库文件 只计算正在使用的页面 不包括空闲页
+pub fn get_database_used_bytes() -> Result<i64> {
+    let client = client()?;
+    let (page_count, freelist_count, page_size): (i64, i64, i64) = client.query_row(
+        "SELECT * FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+        (),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    Ok((page_count - freelist_count) * page_size)
+}
+
+// 数据库和图片文件实际使用的字节数
+pub fn get_used_bytes() -> Result<i64> {
+    Ok(get_database_used_bytes()? + blob::usage()? as i64)
+}
+
+fn get_rows(sql: &str) -> Result<Vec<Row>> {
+    let client = client()?;
+    let mut stmt = client.prepare(sql)?;
+    let rows = stmt.query_map(named_params! {}, |row| Ok(Row {
+        id: row.get(0)?,
+        size: row.get(1)?,
+        ctime: row.get(2)?,
+        mtime: row.get(3)?,
+        used: row.get(4)?,
+        pinned: row.get(5)?,
+        day: row.get(6)?,
+        bytes: row.get(7)?,
+    }))?.collect::<rusqlite::Result<Vec<Row>>>()?;
+    Ok(rows)
+}
+
+fn candidate(row: &Row, reason: CleanReason, purge: bool) -> CleanCandidate {
+    CleanCandidate { id: row.id, size: row.size, ctime: row.ctime, mtime: row.mtime, reason, purge }
+}
+
+// 计算满足保留策略需要删除的图片 按删除顺序排列 不会修改数据
+// 按时间、每天数量和总数量删除的图片移入回收站 按空间删除的图片需要彻底删除才能释放空间
+pub fn plan(policy: &RetentionPolicy) -> Result<Vec<CleanCandidate>> {
+    let order = match policy.order {
+        CleanOrder::Oldest => "ctime, id",
+        CleanOrder::LeastRecentlyUsed => "IFNULL(last_used_at, mtime), id",
+        CleanOrder::Largest => "size DESC, id",
+    };
+    let images = get_rows(format!(r#"SELECT id, size, ctime, mtime, IFNULL(last_used_at, mtime), pinned, DATE(ctime / 1000, 'unixepoch', 'localtime'),
+        size + IFNULL(LENGTH(thumbnail), 0) FROM image WHERE deleted_at IS NULL ORDER BY {}"#, order).as_str())?;
+    let mut ret: Vec<CleanCandidate> = vec![];
+    let mut chosen = HashSet::new();
+    if policy.max_age_days > 0 {
+        let before = chrono::Local::now().timestamp_millis() - policy.max_age_days * DAY_MILLIS;
+        for row in images.iter().filter(|row| !row.pinned && row.used < before) {
+            chosen.insert(row.id);
+            ret.push(candidate(row, CleanReason::Age, false));
+        }
+    }
+    if policy.daily_limit > 0 {
+        // 置顶的图片同样计入当天的数量
+        let mut days: HashMap<&str, i64> = HashMap::new();
+        for row in images.iter().filter(|row| !chosen.contains(&row.id)) {
+            *days.entry(row.day.as_str()).or_default() += 1;
+        }
+        for row in images.iter().filter(|row| !row.pinned) {
+            let count = days.get_mut(row.day.as_str());
+            if let Some(count) = count {
+                if *count > policy.daily_limit && chosen.insert(row.id) {
+                    *count -= 1;
+                    ret.push(candidate(row, CleanReason::Daily, false));
+                }
+            }
+        }
+    }
+    if policy.max_count > 0 {
+        let mut count = (images.len() - chosen.len()) as i64;
+        for row in images.iter().filter(|row| !row.pinned) {
+            if count <= policy.max_count {
+                break;
+            }
+            if chosen.insert(row.id) {
+                count -= 1;
+                ret.push(candidate(row, CleanReason::Count, false));
+            }
+        }
+        if count > policy.max_count {
+            warn!("retention policy: pinned images alone exceed the count limit");
+        }
+    }
+    if policy.max_mb > 0 {
+        // 先删除回收站中最早删除的图片 再按顺序删除其他图片
+        let mut over = get_used_bytes()? - policy.max_mb * MB;
+        let trash = get_rows(r#"SELECT id, size, ctime, mtime, IFNULL(last_used_at, mtime), pinned, '', size + IFNULL(LENGTH(thumbnail), 0)
+            FROM image WHERE deleted_at IS NOT NULL ORDER BY deleted_at, id"#)?;
+        let index: HashMap<i64, usize> = ret.iter().enumerate().map(|(i, c)| (c.id, i)).collect();
+        for row in trash.iter().chain(images.iter().filter(|row| !row.pinned)) {
+            if over <= 0 {
+                break;
+            }
+            over -= row.bytes;
+            // 已经因为其他策略删除的图片改为彻底删除
+            match index.get(&row.id) {
+                Some(&i) => ret[i].purge = true,
+                None => ret.push(candidate(row, CleanReason::Size, true)),
+            }
+        }
+        if over > 0 {
+            warn!("retention policy: pinned images alone exceed the size limit");
+        }
+    }
+    Ok(ret)
+}
+
+// 按计划分批删除 返回删除的数量
+pub fn apply(candidates: &[CleanCandidate]) -> Result<usize> {
+    let ids = candidates.iter().map(|c| c.id).collect::<Vec<i64>>();
+    for chunk in ids.chunks(BATCH) {
+        image_trash::trash_image(&chunk.to_vec())?;
+    }
+    let ids = candidates.iter().filter(|c| c.purge).map(|c| c.id).collect::<Vec<i64>>();
+    for chunk in ids.chunks(BATCH) {
+        image_trash::purge_trash(&Some(chunk.to_vec()))?;
+    }
+    Ok(candidates.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::image_insert::{insert_image, ImageAnalysis, SOURCE_UPLOAD};
+    use crate::client::sqlite::testing;
+    use super::*;
+
+    // 不可压缩的图片数据
+    fn noise(seed: usize, len: usize) -> Vec<u8> {
+        let mut ret = vec![];
+        let mut digest = seed.to_string();
+        while ret.len() < len {
+            digest = sha256::digest(digest);
+            ret.extend_from_slice(digest.as_bytes());
+        }
+        ret.truncate(len);
+        ret
+    }
+
+    // 超过空间上限时反复计划和删除 最终占用不超过上限
+    #[test]
+    fn plan_until_under_max_mb() {
+        let _lock = testing::reset();
+        for i in 0..40 {
+            let image = noise(i, 80 * 1024);
+            let analysis = ImageAnalysis { sum: sha256::digest(image.as_slice()), phash: 0, thumbnail: vec![], palette: vec![] };
+            insert_image(&image, &1, &1, &analysis, SOURCE_UPLOAD).unwrap();
+        }
+        assert!(get_used_bytes().unwrap() > 3 * MB);
+        let policy = RetentionPolicy { max_age_days: 0, max_count: 0, max_mb: 1, daily_limit: 0, order: CleanOrder::Oldest };
+        let mut rounds = 0;
+        loop {
+            let candidates = plan(&policy).unwrap();
+            if candidates.is_empty() {
+                break;
+            }
+            assert!(candidates.iter().all(|c| c.purge));
+            apply(&candidates).unwrap();
+            rounds += 1;
+            assert!(rounds <= 5, "retention plan does not converge");
+        }
+        assert!(get_used_bytes().unwrap() <= policy.max_mb * MB);
+        let left: i64 = client().unwrap().query_row("SELECT COUNT(*) FROM image", (), |row| row.get(0)).unwrap();
+        assert!(left > 0);
+    }
+
+    // 最近使用过的图片即使很早保存也不会按时间删除 按最少使用排序时排在后面
+    #[test]
+    fn plan_by_last_used() {
+        let _lock = testing::reset();
+        let now = chrono::Local::now().timestamp_millis();
+        let old = now - 10 * DAY_MILLIS;
+        let client = client().unwrap();
+        for (id, mtime, last_used_at) in [(1, old, Some(now)), (2, old, None), (3, now + 1, None), (4, old - DAY_MILLIS, Some(old + 1))] {
+            client.execute(r#"INSERT INTO image (id, size, width, height, ctime, mtime, sum, last_used_at)
+                VALUES (?1, 1, 1, 1, ?2, ?2, ?3, ?4)"#, (id, mtime, format!("{:064}", id), last_used_at)).unwrap();
+        }
+        drop(client);
+        let policy = RetentionPolicy { max_age_days: 5, max_count: 0, max_mb: 0, daily_limit: 0, order: CleanOrder::LeastRecentlyUsed };
+        let ids = plan(&policy).unwrap().iter().map(|c| c.id).collect::<Vec<i64>>();
+        assert_eq!(ids, vec![2, 4]);
+        let policy = RetentionPolicy { max_age_days: 0, max_count: 1, ..policy };
+        let ids = plan(&policy).unwrap().iter().map(|c| c.id).collect::<Vec<i64>>();
+        assert_eq!(ids, vec![2, 4, 1]);
+    }
+}
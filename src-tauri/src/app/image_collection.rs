@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Result};
+use rusqlite::{named_params, Connection, TransactionBehavior};
+use crate::client::blob;
+use crate::client::query::Query;
+use crate::client::sqlite::client;
+use crate::model::Collection;
+
+fn check_name(name: &str) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        bail!("collection name can not be empty");
+    }
+    Ok(name.to_string())
+}
+
+fn check_exist(client: &Connection, collection_id: i64) -> Result<()> {
+    let mut stmt = client.prepare_cached("SELECT 1 FROM collection WHERE id = :id")?;
+    let mut rows = stmt.query(named_params! {
+        ":id": collection_id,
+    })?;
+    if rows.next()?.is_none() {
+        bail!("no such collection id: {}", collection_id);
+    }
+    Ok(())
+}
+
+// 按顺序排列的图片ID
+fn get_image_id(client: &Connection, collection_id: i64) -> Result<Vec<i64>> {
+    let mut stmt = client.prepare_cached(
+        "SELECT image_id FROM collection_image WHERE collection_id = :id ORDER BY position")?;
+    let mut rows = stmt.query(named_params! {
+        ":id": collection_id,
+    })?;
+    let mut ret = vec![];
+    while let Some(row) = rows.next()? {
+        ret.push(row.get(0)?);
+    }
+    Ok(ret)
+}
+
+// 新建的图集排在最后
+pub fn create_collection(name: &str) -> Result<Collection> {
+    let name = check_name(name)?;
+    let client = client()?;
+    let now = chrono::Local::now().timestamp_millis();
+    client.execute(r#"INSERT INTO collection (name, position, ctime)
+                   VALUES (?1, (SELECT IFNULL(MAX(position), -1) + 1 FROM collection), ?2)"#, (&name, &now))?;
+    Ok(Collection {
+        id: client.last_insert_rowid(),
+        name,
+        count: 0,
+        ctime: now,
+    })
+}
+
+pub fn rename_collection(collection_id: i64, name: &str) -> Result<()> {
+    let name = check_name(name)?;
+    let client = client()?;
+    if client.execute("UPDATE collection SET name = ?2 WHERE id = ?1", (&collection_id, &name))? == 0 {
+        bail!("no such collection id: {}", collection_id);
+    }
+    Ok(())
+}
+
+// 删除图集 图集中的图片本身不会被删除
+pub fn delete_collection(collection_id: i64) -> Result<()> {
+    let client = client()?;
+    client.execute("DELETE FROM collection WHERE id = ?1", (&collection_id,))?;
+    Ok(())
+}
+
+// 全部图集 按图集顺序排列
+pub fn get_collection() -> Result<Vec<Collection>> {
+    let client = client()?;
+    let mut stmt = client.prepare_cached(r#"SELECT id, name, ctime,
+        (SELECT COUNT(*) FROM collection_image AS c JOIN image ON image.id = c.image_id
+         WHERE c.collection_id = collection.id AND image.deleted_at IS NULL)
+        FROM collection ORDER BY position"#)?;
+    let mut rows = stmt.query(named_params! {})?;
+    let mut ret = vec![];
+    while let Some(row) = rows.next()? {
+        ret.push(Collection {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            ctime: row.get(2)?,
+            count: row.get(3)?,
+        });
+    }
+    Ok(ret)
+}
+
+// 按给定的顺序重新排列图集 必须包含全部图集
+pub fn reorder_collection(collection_id: &Vec<i64>) -> Result<()> {
+    let mut client = client()?;
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let count: i64 = tx.query_row("SELECT COUNT(*) FROM collection", (), |row| row.get(0))?;
+    let unique: HashSet<&i64> = collection_id.iter().collect();
+    if unique.len() != collection_id.len() || count != collection_id.len() as i64 {
+        bail!("reorder collection must contain every collection exactly once");
+    }
+    for (position, id) in collection_id.iter().enumerate() {
+        if tx.execute("UPDATE collection SET position = ?2 WHERE id = ?1", (id, &(position as i64)))? == 0 {
+            bail!("no such collection id: {}", id);
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// 把图片按顺序追加到图集末尾 已经在图集中的图片保持原位置
+pub fn add_collection_image(collection_id: i64, image_id: &Vec<i64>) -> Result<()> {
+    let mut client = client()?;
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    check_exist(&tx, collection_id)?;
+    {
+        let mut stmt = tx.prepare_cached(r#"INSERT OR IGNORE INTO collection_image (collection_id, image_id, position)
+            VALUES (?1, ?2, (SELECT IFNULL(MAX(position), -1) + 1 FROM collection_image WHERE collection_id = ?1))"#)?;
+        for image_id in image_id {
+            stmt.execute((&collection_id, image_id))?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn remove_collection_image(collection_id: i64, image_id: &Vec<i64>) -> Result<()> {
+    let client = client()?;
+    let mut query = Query::default();
+    query.push_bind("DELETE FROM collection_image WHERE collection_id = ? AND image_id IN", [collection_id])
+        .push_list(image_id.iter().cloned());
+    client.execute(query.sql(), query.params())?;
+    Ok(())
+}
+
+// 按给定的顺序重新排列图集中的图片 必须包含图集中的全部图片
+pub fn reorder_collection_image(collection_id: i64, image_id: &Vec<i64>) -> Result<()> {
+    let mut client = client()?;
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    check_exist(&tx, collection_id)?;
+    let current: HashSet<i64> = get_image_id(&tx, collection_id)?.into_iter().collect();
+    let target: HashSet<i64> = image_id.iter().cloned().collect();
+    if target.len() != image_id.len() || current != target {
+        bail!("reorder collection image must contain every image of the collection exactly once");
+    }
+    {
+        let mut stmt = tx.prepare_cached(
+            "UPDATE collection_image SET position = ?3 WHERE collection_id = ?1 AND image_id = ?2")?;
+        for (position, image_id) in image_id.iter().enumerate() {
+            stmt.execute((&collection_id, image_id, &(position as i64)))?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// 按图集顺序把原图导出到目录中 回收站中的图片不导出 文件名为序号 返回导出的图片数量
+pub fn export_collection(collection_id: i64, directory: &str) -> Result<usize> {
+    let directory = Path::new(directory);
+    if !directory.is_dir() {
+        bail!("export directory not found: {}", directory.display());
+    }
+    let client = client()?;
+    check_exist(&client, collection_id)?;
+    let mut stmt = client.prepare_cached(r#"SELECT image.sum FROM collection_image
+        JOIN image ON image.id = collection_image.image_id
+        WHERE collection_image.collection_id = :id AND image.deleted_at IS NULL
+        ORDER BY collection_image.position"#)?;
+    let mut rows = stmt.query(named_params! {
+        ":id": collection_id,
+    })?;
+    let mut sums: Vec<String> = vec![];
+    while let Some(row) = rows.next()? {
+        sums.push(row.get(0)?);
+    }
+    let width = sums.len().to_string().len().max(3);
+    for (index, sum) in sums.iter().enumerate() {
+        let path = directory.join(format!("{:0width$}.png", index + 1, width = width));
+        fs::write(path, blob::get(sum)?)?;
+    }
+    Ok(sums.len())
+}
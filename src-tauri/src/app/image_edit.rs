@@ -0,0 +1,30 @@
+use anyhow::{bail, Result};
+use rusqlite::TransactionBehavior;
+use crate::client::{crypto, fts};
+use crate::client::sqlite::client;
+
+// 去掉首尾空白 空字符串视为清除
+fn normalize(value: Option<String>) -> Option<String> {
+    value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+// 更新一列文本并同步全文索引
+fn update_column(image_id: i64, sql: &str, value: Option<String>) -> Result<()> {
+    let value = crypto::seal_text(normalize(value).as_deref())?;
+    let mut client = client()?;
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    if tx.execute(sql, (&image_id, &value))? == 0 {
+        bail!("no such image id: {}", image_id);
+    }
+    fts::update(&tx, image_id)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn set_title(image_id: i64, title: Option<String>) -> Result<()> {
+    update_column(image_id, "UPDATE image SET title = ?2 WHERE id = ?1", title)
+}
+
+pub fn set_note(image_id: i64, note: Option<String>) -> Result<()> {
+    update_column(image_id, "UPDATE image SET note = ?2 WHERE id = ?1", note)
+}
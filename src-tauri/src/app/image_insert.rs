@@ -4,36 +4,75 @@ use std::path::PathBuf;
 use std::sync::Mutex;
 use anyhow::{bail, Result};
 use arboard::ImageData;
-use image::EncodableLayout;
+use image::{EncodableLayout, GenericImageView, Rgba};
 use log::error;
 use once_cell::sync::Lazy;
-use rusqlite::named_params;
+use rusqlite::{named_params, OptionalExtension, TransactionBehavior};
+use crate::analyzer::{palette, phash};
+use crate::analyzer::thumbnail::make_thumbnail;
+use crate::client::{blob, crypto};
 use crate::client::sqlite::client;
-use crate::common::get_root;
+use crate::clipboard;
+use crate::common::{get_root, pixel_sum};
+use crate::regular::PAUSE;
 
-// 数据库中插入图片
-pub fn insert_image(image: &Vec<u8>, width: &i32, height: &i32, sum: &String) -> Result<()> {
-    let client = client();
-    // 校验是否是上一次插入的图片
-    let mut stmt = client.prepare("SELECT id, sum FROM image ORDER BY mtime DESC LIMIT 1")?;
-    let mut rows = stmt.query(named_params! {})?;
-    let mut last_sum = "-".to_string();
-    let mut id = 0;
-    while let Some(row) = rows.next()? {
-        id = row.get(0)?;
-        last_sum = row.get(1)?;
+// 图片来源
+pub const SOURCE_CLIPBOARD: &str = "clipboard";
+pub const SOURCE_UPLOAD: &str = "upload";
+
+// 由图片像素计算出的数据 与图片一起插入
+pub struct ImageAnalysis {
+    pub sum: String,
+    pub phash: i64,
+    pub thumbnail: Vec<u8>,
+    pub palette: Vec<u8>,
+}
+
+impl ImageAnalysis {
+    // 由图片像素计算 sum由调用方事先计算
+    pub fn new<I: GenericImageView<Pixel = Rgba<u8>>>(sum: String, pixels: &I) -> Result<ImageAnalysis> {
+        Ok(ImageAnalysis {
+            sum,
+            phash: phash::dhash(pixels),
+            thumbnail: make_thumbnail(pixels)?,
+            palette: palette::make_palette(pixels),
+        })
     }
+}
+
+// 数据库中插入图片
+pub fn insert_image(image: &Vec<u8>, width: &i32, height: &i32, analysis: &ImageAnalysis, source: &str) -> Result<()> {
+    let ImageAnalysis { sum, phash, thumbnail, palette } = analysis;
+    let thumbnail = crypto::seal(thumbnail)?;
+    let mut client = client()?;
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
     let now = chrono::Local::now().timestamp_millis();
-    if &last_sum == sum {
-        // 设置修改时间
-        client.execute(r#"UPDATE image SET mtime = ?2 WHERE id = ?1"#, (&id, &now))?;
-        return Ok(());
-    }
-    // 正式开始插入图片
-    let size = image.len() as i64;
-    client.execute(r#"INSERT INTO image (image, size, width, height, ctime, mtime, sum)
-                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
-                   (image, &size, width, height, &now, &now, sum))?;
+    // 在整个历史中查找相同的图片
+    let id: Option<i64> = tx.query_row(
+        "SELECT id FROM image WHERE sum = :sum LIMIT 1",
+        named_params! { ":sum": sum },
+        |row| row.get(0),
+    ).optional()?;
+    let id = match id {
+        Some(id) => {
+            // 已经存在 设置修改时间使其排到最前 在回收站中的图片会被恢复
+            tx.execute(r#"UPDATE image SET mtime = ?2, deleted_at = NULL WHERE id = ?1"#, (&id, &now))?;
+            id
+        }
+        None => {
+            // 正式开始插入图片 图片本身保存到存储中 数据库只记录元数据
+            blob::put(sum, image.as_slice())?;
+            let size = image.len() as i64;
+            tx.execute(r#"INSERT INTO image (size, width, height, ctime, mtime, sum, phash, thumbnail, palette)
+                       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+                       (&size, width, height, &now, &now, sum, phash, &thumbnail, palette))?;
+            tx.last_insert_rowid()
+        }
+    };
+    // 记录本次出现
+    tx.execute(r#"INSERT INTO image_occurrence (image_id, ctime, source) VALUES (?1, ?2, ?3)"#,
+               (&id, &now, source))?;
+    tx.commit()?;
     Ok(())
 }
 
@@ -46,7 +85,14 @@ static CACHE_PATH: Lazy<PathBuf> = Lazy::new(|| {
     root.join("cache.1.png")
 });
 
-fn save_image_inner(data: ImageData) -> Result<()> {
+fn save_image_inner(data: ImageData, source: &str) -> Result<()> {
+    // 加密的历史在解锁前无法保存新的图片
+    crypto::check_unlocked()?;
+    let sum = pixel_sum(data.width as u32, data.height as u32, data.bytes.as_ref());
+    // 从历史中再次复制的图片已经记录了使用 不作为新的复制保存
+    if source == SOURCE_CLIPBOARD && clipboard::take_re_copied(&sum) {
+        return Ok(());
+    }
     let image;
     {
         let lock = LOCK.lock();
@@ -61,14 +107,24 @@ fn save_image_inner(data: ImageData) -> Result<()> {
             image::ColorType::Rgba8,
         )?;
         image = fs::read(CACHE_PATH.as_path())?;
+        // 开启加密时不在磁盘上留下明文
+        if crypto::is_enabled() {
+            fs::remove_file(CACHE_PATH.as_path())?;
+        }
     }
-    let sum = sha256::digest(image.as_slice());
-    insert_image(&image, &(data.width.clone() as i32), &(data.height.clone() as i32), &sum)?;
+    let pixels = image::ImageBuffer::<image::Rgba<u8>, &[u8]>::from_raw(data.width as u32, data.height as u32, data.bytes.as_ref());
+    let pixels = match pixels {
+        Some(pixels) => pixels,
+        None => bail!("invalid image data with size {}x{}", data.width, data.height),
+    };
+    let analysis = ImageAnalysis::new(sum, &pixels)?;
+    insert_image(&image, &(data.width.clone() as i32), &(data.height.clone() as i32), &analysis, source)?;
     Ok(())
 }
 
 // 上传图片
 pub async fn upload_image(image_path: &Vec<String>) -> Result<()> {
+    let _pause = PAUSE.read().await;
     let mut img = vec![];
     for path in image_path {
         let data = fs::read(path)?;
@@ -81,15 +137,85 @@ pub async fn upload_image(image_path: &Vec<String>) -> Result<()> {
             height: img.height() as usize,
             bytes: Cow::Borrowed(img.as_bytes()),
         };
-        save_image_inner(img)?;
+        save_image_inner(img, SOURCE_UPLOAD)?;
     }
     Ok(())
 }
 
-// 保存图片
+// 加密的历史解锁前复制的图片先放在内存中 解锁后再保存 超出数量时丢弃最早的
+const PENDING_LIMIT: usize = 16;
+
+static PENDING: Lazy<Mutex<Vec<ImageData<'static>>>> = Lazy::new(|| {
+    Mutex::new(vec![])
+});
+
+// 保存图片 恢复备份期间等待恢复完成
 pub fn save_image(data: ImageData) {
-    let result = save_image_inner(data);
+    let _pause = PAUSE.blocking_read();
+    {
+        // 持有队列的锁再检查是否解锁 避免与save_pending交错导致图片留在队列中
+        let mut pending = PENDING.lock().unwrap_or_else(|err| err.into_inner());
+        if crypto::is_locked() {
+            if pending.len() >= PENDING_LIMIT {
+                pending.remove(0);
+                error!("too many images copied before unlock, drop the oldest");
+            }
+            pending.push(ImageData {
+                width: data.width,
+                height: data.height,
+                bytes: Cow::Owned(data.bytes.into_owned()),
+            });
+            return;
+        }
+    }
+    let result = save_image_inner(data, SOURCE_CLIPBOARD);
     if let Err(err) = result {
         error!("save image error: {}", err);
     }
-}
\ No newline at end of file
+}
+
+// 解锁后保存解锁前复制的图片
+pub fn save_pending() {
+    let pending = {
+        let mut pending = PENDING.lock().unwrap_or_else(|err| err.into_inner());
+        std::mem::take(&mut *pending)
+    };
+    for data in pending {
+        save_image(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::app::image_trash;
+    use crate::client::sqlite::testing;
+    use super::*;
+
+    // 多个线程同时插入、移入回收站和彻底删除 不会出现SQLITE_BUSY
+    #[test]
+    fn concurrent_insert_and_purge() {
+        let _lock = testing::reset();
+        let handles: Vec<_> = (0..8).map(|t| thread::spawn(move || -> Result<()> {
+            for i in 0..25 {
+                // 一半的图片在线程之间重复 插入时先查询再更新
+                let n = if i % 2 == 0 { i } else { t * 100 + i };
+                let image = format!("image-{}", n).into_bytes();
+                let analysis = ImageAnalysis { sum: sha256::digest(image.as_slice()), phash: 0, thumbnail: vec![], palette: vec![] };
+                insert_image(&image, &1, &1, &analysis, SOURCE_UPLOAD)?;
+                let id: Option<i64> = client()?.query_row("SELECT id FROM image WHERE sum = ?1", (&analysis.sum,), |row| row.get(0)).optional()?;
+                // 其他线程可能已经删除了相同的图片
+                if let Some(id) = id {
+                    image_trash::trash_image(&vec![id])?;
+                    image_trash::purge_trash(&Some(vec![id]))?;
+                }
+            }
+            Ok(())
+        })).collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+        let count: i64 = client().unwrap().query_row("SELECT COUNT(*) FROM image", (), |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
+}
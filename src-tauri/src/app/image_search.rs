@@ -1,157 +1,812 @@
 use std::ops::Not;
 use anyhow::{Result, bail};
-use color_space::{CompareCie2000, Lab, Rgb};
-use format_sql_query::QuotedData;
-use image::{GenericImageView, load_from_memory};
-use rusqlite::named_params;
-use crate::app::{ColorFilter, GetImageRequest};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use color_space::{Lab, Rgb};
+use image::load_from_memory;
+use rusqlite::{named_params, OptionalExtension};
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+use crate::app::{image_tag, ColorFilter, GetImageRequest, ImageSortField, Orientation, SimilarTo, SortDirection, TagFilterMode};
+use crate::analyzer::palette;
+use crate::analyzer::thumbnail::make_thumbnail;
+use crate::client::{blob, crypto, fts};
+use crate::client::fts::TextQuery;
+use crate::client::query::Query;
 use crate::client::sqlite::client;
-use crate::model::{Image, ImageData};
+use crate::model::{Image, ImageData, ImageOccurrence, ImagePage};
 
-fn gen_where_sql(request: &GetImageRequest) -> String {
-    let mut sql = "".to_string();
+// 宽高比 与index_aspect_ratio的表达式相同
+const ASPECT_RATIO: &str = "CAST(width AS REAL) / height";
+
+fn gen_where(request: &GetImageRequest) -> Query {
+    let mut query = Query::default();
+    if request.trash.is_some_and(|x| x) {
+        query.push("AND deleted_at IS NOT NULL");
+    } else {
+        query.push("AND deleted_at IS NULL");
+    }
     if let Some(mtime) = &request.mtime {
-        sql.push_str(format!(" AND mtime < {} ", mtime).as_str());
+        query.push_bind("AND mtime < ?", [*mtime]);
     }
     if let Some(id) = &request.id {
         if id.len() > 0 {
-            let v: Vec<String> = id.iter().map(|v| v.to_string()).collect();
-            let s = v.join(", ");
-            sql.push_str(format!(" AND id IN ({}) ", s).as_str());
+            query.push("AND id IN").push_list(id.iter().cloned());
         }
     }
-    if let Some(text) = &request.text {
+    // 开启加密后没有全文索引 解密后再按文本过滤
+    if let Some(text) = request.text.as_ref().filter(|_| !crypto::is_enabled()) {
         if text.len() > 0 {
-            let s: Vec<String> = text.iter().map(|v| {
-                format!(r#" (id IN (
-                    SELECT image.id FROM image, JSON_EACH(JSON_EXTRACT(ocr, '$.data')) AS j
-                    WHERE JSON_EXTRACT(image.ocr, '$.code') = 100
-                        AND JSON_EXTRACT(j.value, '$.text') LIKE '%' || {} || '%'
-                )) "#, QuotedData(v))
-            }).collect();
-            sql.push_str(format!(" AND ({}) ", s.join(" OR ")).as_str());
+            query.push("AND (");
+            for (i, v) in text.iter().enumerate() {
+                if i > 0 {
+                    query.push("OR");
+                }
+                match fts::parse_query(v) {
+                    TextQuery::Match(text) => {
+                        query.push_bind("id IN (SELECT rowid FROM image_fts WHERE image_fts MATCH ?)", [text]);
+                    }
+                    TextQuery::Like(text) => {
+                        // 搜索词中的%和_按原样匹配
+                        let like = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+                        query.push_bind(r"id IN (SELECT rowid FROM image_fts WHERE text LIKE ? ESCAPE '\' OR title LIKE ? ESCAPE '\' OR note LIKE ? ESCAPE '\')",
+                                        [like.clone(), like.clone(), like]);
+                    }
+                }
+            }
+            query.push(")");
         }
     }
     if let Some(date_range_from) = &request.date_range_from {
-        sql.push_str(format!(" AND ctime >= {} ", date_range_from).as_str());
+        query.push_bind("AND ctime >= ?", [*date_range_from]);
     }
     if let Some(date_range_to) = &request.date_range_to {
-        sql.push_str(format!(" AND ctime <= {} ", date_range_to).as_str());
+        query.push_bind("AND ctime <= ?", [*date_range_to]);
+    }
+    let ranges = [
+        ("width", request.width_from.map(i64::from), request.width_to.map(i64::from)),
+        ("height", request.height_from.map(i64::from), request.height_to.map(i64::from)),
+        ("size", request.size_from, request.size_to),
+    ];
+    for (column, from, to) in ranges {
+        if let Some(from) = from {
+            query.push_bind(format!("AND {} >= ?", column).as_str(), [from]);
+        }
+        if let Some(to) = to {
+            query.push_bind(format!("AND {} <= ?", column).as_str(), [to]);
+        }
+    }
+    // 与index_aspect_ratio的表达式一致才能使用索引
+    if let Some(aspect_ratio_from) = &request.aspect_ratio_from {
+        query.push_bind(format!("AND {} >= ?", ASPECT_RATIO).as_str(), [*aspect_ratio_from]);
+    }
+    if let Some(aspect_ratio_to) = &request.aspect_ratio_to {
+        query.push_bind(format!("AND {} <= ?", ASPECT_RATIO).as_str(), [*aspect_ratio_to]);
+    }
+    if let Some(orientation) = &request.orientation {
+        let op = match orientation {
+            Orientation::Portrait => "<",
+            Orientation::Landscape => ">",
+            Orientation::Square => "=",
+        };
+        query.push(format!("AND {} {} 1", ASPECT_RATIO, op).as_str());
+    }
+    if let Some(tags) = &request.tags {
+        if tags.id.len() > 0 {
+            query.push("AND id IN (SELECT image_id FROM image_tag WHERE tag_id IN").push_list(tags.id.iter().cloned());
+            match tags.mode {
+                TagFilterMode::ANY => query.push(")"),
+                TagFilterMode::ALL => {
+                    let mut tag_id = tags.id.clone();
+                    tag_id.sort();
+                    tag_id.dedup();
+                    query.push_bind("GROUP BY image_id HAVING COUNT(*) = ?)", [tag_id.len() as i64])
+                }
+            };
+        }
+    }
+    if let Some(pinned) = &request.pinned {
+        query.push_bind("AND pinned = ?", [*pinned]);
+    }
+    if let Some(collection_id) = &request.collection_id {
+        query.push_bind("AND id IN (SELECT image_id FROM collection_image WHERE collection_id = ?)", [*collection_id]);
+    }
+    // 还没有补充计算直方图的图片在读取后按像素过滤
+    for color_filter in gen_color_filters(request) {
+        query.push("AND (palette IS NULL OR").append(&gen_coverage(color_filter))
+            .push_bind("BETWEEN ? AND ?)", [color_filter.cover_ratio_from, color_filter.cover_ratio_to]);
+    }
+    if let Some(similar_to) = &request.similar_to {
+        query.push_bind("AND id != ? AND", [similar_to.id])
+            .append(&gen_distance(similar_to))
+            .push_bind("<= ?", [similar_to.max_distance as i64]);
+    }
+    query
+}
+
+// 所有搜索词都能使用FTS5匹配时 返回合并后的查询表达式 用于生成命中片段和按BM25相关度排序
+fn gen_match_query(request: &GetImageRequest) -> Option<String> {
+    if crypto::is_enabled() {
+        return None;
+    }
+    let text = request.text.as_ref()?;
+    if text.is_empty() {
+        return None;
+    }
+    let mut query = vec![];
+    for v in text {
+        match fts::parse_query(v) {
+            TextQuery::Match(q) => query.push(format!("({})", q)),
+            TextQuery::Like(_) => return None,
+        }
+    }
+    Some(query.join(" OR "))
+}
+
+// 排序条件中的一项 同时是翻页游标中的一个键
+struct SortTerm {
+    expr: Query,
+    desc: bool,
+    // 值可能为NULL NULL排在最小的位置
+    nullable: bool,
+}
+
+impl SortTerm {
+    fn new(expr: &str, desc: bool, nullable: bool) -> SortTerm {
+        SortTerm { expr: Query::new(expr), desc, nullable }
+    }
+}
+
+// 按BM25相关度排序时的查询表达式 指定了排序方式或相似图片时不按相关度排序
+fn gen_rank_query(request: &GetImageRequest) -> Option<String> {
+    if request.sort.is_some() || request.similar_to.is_some() {
+        return None;
+    }
+    gen_match_query(request)
+}
+
+// 结果的排序方式 最后一项总是ID 保证相同值之间的顺序稳定
+// 按相关度排序时使用snapshot指定的相关度快照
+fn gen_order(request: &GetImageRequest, snapshot: Option<u64>) -> Vec<SortTerm> {
+    let mut terms = vec![];
+    if request.pinned_first.is_some_and(|x| x) {
+        // 置顶的图片排在最前
+        terms.push(SortTerm::new("pinned", true, false));
+    }
+    let mut desc = true;
+    if let Some(sort) = &request.sort {
+        // 指定的排序方式优先于相似程度、相关度和图集顺序
+        desc = sort.direction == SortDirection::DESC;
+        terms.push(match sort.field {
+            ImageSortField::Ctime => SortTerm::new("ctime", desc, false),
+            ImageSortField::Mtime => SortTerm::new("mtime", desc, false),
+            ImageSortField::Size => SortTerm::new("size", desc, false),
+            ImageSortField::UseCount => SortTerm::new("use_count", desc, false),
+            ImageSortField::LastUsed => SortTerm::new("last_used_at", desc, true),
+        });
+    } else if let Some(similar_to) = &request.similar_to {
+        // 按相似程度排序 距离越小越靠前
+        terms.push(SortTerm { expr: gen_distance(similar_to), desc: false, nullable: true });
+        terms.push(SortTerm::new("mtime", true, false));
+    } else if let Some(snapshot) = snapshot {
+        // 按BM25相关度排序 值越小越相关 快照之后才匹配的图片没有相关度 排在最前 不会出现在之后的页面
+        let mut expr = Query::default();
+        expr.push_bind("fts_rank(?, image.id)", [snapshot as i64]);
+        terms.push(SortTerm { expr, desc: false, nullable: true });
+        terms.push(SortTerm::new("mtime", true, false));
+    } else if !gen_color_filters(request).is_empty() {
+        // 按覆盖比例之和排序 比例越大越靠前 没有直方图的图片排在最后
+        let mut expr = Query::new("(");
+        for (i, color_filter) in gen_color_filters(request).into_iter().enumerate() {
+            if i > 0 {
+                expr.push("+");
+            }
+            expr.append(&gen_coverage(color_filter));
+        }
+        expr.push(")");
+        terms.push(SortTerm { expr, desc: true, nullable: true });
+        terms.push(SortTerm::new("mtime", true, false));
+    } else if let Some(collection_id) = &request.collection_id {
+        // 按图集中的顺序排序
+        let mut expr = Query::default();
+        expr.push_bind("(SELECT position FROM collection_image AS c WHERE c.collection_id = ? AND c.image_id = image.id)",
+                       [*collection_id]);
+        terms.push(SortTerm { expr, desc: false, nullable: false });
+    } else {
+        terms.push(SortTerm::new("mtime", true, false));
+    }
+    terms.push(SortTerm::new("id", desc, false));
+    terms
+}
+
+// 游标中的键 与排序条件一一对应
+// 浮点数按位保存 解析JSON时不会损失精度 保证比较相等时结果一致
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum CursorKey {
+    #[serde(rename = "i")]
+    Integer(i64),
+    #[serde(rename = "r")]
+    Real(u64),
+    #[serde(rename = "n")]
+    Null,
+}
+
+impl CursorKey {
+    fn from_value(value: Value) -> Result<CursorKey> {
+        Ok(match value {
+            Value::Integer(v) => CursorKey::Integer(v),
+            Value::Real(v) => CursorKey::Real(v.to_bits()),
+            Value::Null => CursorKey::Null,
+            _ => bail!("invalid sort key"),
+        })
+    }
+
+    fn to_value(&self) -> Value {
+        match self {
+            CursorKey::Integer(v) => Value::Integer(*v),
+            CursorKey::Real(v) => Value::Real(f64::from_bits(*v)),
+            CursorKey::Null => Value::Null,
+        }
     }
-    sql
 }
 
-async unsafe fn do_color_filter_inner(image: &image::RgbImage, x: u32, y: u32, color: &Lab, difference: &f64) -> bool {
-    let p = image.unsafe_get_pixel(x.clone(), y.clone());
-    let c = Lab::from(Rgb::new(p[0].clone() as f64, p[1].clone() as f64, p[2].clone() as f64));
-    let delta_e = color.compare_cie2000(&c);
-    difference >= &delta_e
+// 游标对调用方不透明 内容是相关度快照ID和最后一张图片的全部排序键
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Cursor {
+    #[serde(rename = "s", default, skip_serializing_if = "Option::is_none")]
+    snapshot: Option<u64>,
+    #[serde(rename = "k")]
+    keys: Vec<CursorKey>,
 }
 
-async fn do_color_filter(image: &Image, color_filter: &ColorFilter) -> Result<bool> {
-    let image = load_from_memory(image.image.must_binary().as_slice())?;
-    let image = image.into_rgb8();
-    let width = image.width();
-    let height = image.height();
-    let color = Lab::from(Rgb::new(color_filter.red.clone() as f64, color_filter.green.clone() as f64, color_filter.blue.clone() as f64));
-    let mut f = vec![];
-    for x in 0..width {
-        for y in 0..height {
-            unsafe { f.push(do_color_filter_inner(&image, x.clone(), y.clone(), &color, &color_filter.difference)); }
+fn encode_cursor(cursor: &Cursor) -> Result<String> {
+    Ok(URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor)?))
+}
+
+fn decode_cursor(cursor: &str) -> Result<Cursor> {
+    match URL_SAFE_NO_PAD.decode(cursor).ok().and_then(|data| serde_json::from_slice(data.as_slice()).ok()) {
+        Some(cursor) => Ok(cursor),
+        None => bail!("invalid cursor: {}", cursor),
+    }
+}
+
+// 排在游标之后的条件 按排序条件逐项比较 前面的项相等时比较下一项
+fn gen_after(terms: &[SortTerm], keys: &[CursorKey]) -> Query {
+    let mut query = Query::default();
+    // 第一项的范围条件 可以使用索引
+    if let (Some(term), Some(key)) = (terms.first(), keys.first()) {
+        if !term.nullable && !matches!(key, CursorKey::Null) {
+            query.push("AND").append(&term.expr).push_bind(if term.desc { "<= ?" } else { ">= ?" }, [key.to_value()]);
         }
     }
-    let mut count: i64 = 0;
-    for f in f {
-        if f.await {
-            count += 1;
+    query.push("AND (0");
+    for i in 0..terms.len() {
+        query.push("OR (1");
+        for (term, key) in terms.iter().zip(keys.iter()).take(i) {
+            query.push("AND").append(&term.expr).push_bind("IS ?", [key.to_value()]);
         }
+        let (term, key) = (&terms[i], &keys[i]);
+        match (key, term.desc) {
+            // NULL最小 升序时之后是全部非NULL的值 降序时之后没有值
+            (CursorKey::Null, false) => query.push("AND").append(&term.expr).push("IS NOT NULL"),
+            (CursorKey::Null, true) => query.push("AND 0"),
+            (_, false) => query.push("AND").append(&term.expr).push_bind("> ?", [key.to_value()]),
+            (_, true) if term.nullable => query.push("AND (").append(&term.expr).push_bind("< ? OR", [key.to_value()])
+                .append(&term.expr).push("IS NULL)"),
+            (_, true) => query.push("AND").append(&term.expr).push_bind("< ?", [key.to_value()]),
+        };
+        query.push(")");
     }
-    // 覆盖率不在区间内会舍弃这个图片
-    let cover_ratio = (count as f64) / (width as f64 * height as f64);
-    Ok(!(cover_ratio < color_filter.cover_ratio_from || cover_ratio > color_filter.cover_ratio_to))
+    query.push(")");
+    query
 }
 
-async fn filter_image(image: &Image, request: &GetImageRequest) -> Result<bool> {
-    if let Some(color_filter) = &request.color_filter {
-        // 使用DeltaE计算颜色差异 计算颜色在图片中的比重
-        // 颜色差异在维基百科中的介绍：https://zh.wikipedia.org/wiki/%E9%A2%9C%E8%89%B2%E5%B7%AE%E5%BC%82
-        if do_color_filter(image, color_filter).await?.not() {
+// 与指定图片感知哈希的汉明距离
+fn gen_distance(similar_to: &SimilarTo) -> Query {
+    let mut query = Query::default();
+    query.push_bind("hamming_distance(phash, (SELECT phash FROM image WHERE id = ?))", [similar_to.id]);
+    query
+}
+
+// 请求中的全部颜色条件 图片需要同时满足
+fn gen_color_filters(request: &GetImageRequest) -> Vec<&ColorFilter> {
+    request.color_filter.iter().chain(request.color_filters.iter().flatten()).collect()
+}
+
+// 直方图中与颜色条件相近的像素占比 没有直方图时为NULL
+fn gen_coverage(color_filter: &ColorFilter) -> Query {
+    let mut query = Query::default();
+    query.push_bind("color_coverage(palette, ?, ?, ?, ?)", [
+        color_filter.red as f64, color_filter.green as f64, color_filter.blue as f64, color_filter.difference,
+    ]);
+    query
+}
+
+// 按像素计算每个颜色条件的覆盖比例 任意一个不在区间内时返回None
+// 原图先缩小再计算 在阻塞线程池中执行 不占用异步运行时的线程
+async fn do_color_filter(data: Vec<u8>, color_filters: Vec<ColorFilter>) -> Result<Option<Vec<f64>>> {
+    tokio::task::spawn_blocking(move || {
+        let image = palette::downsample(&load_from_memory(data.as_slice())?);
+        let mut ret = vec![];
+        for color_filter in color_filters {
+            let color = Lab::from(Rgb::new(color_filter.red as f64, color_filter.green as f64, color_filter.blue as f64));
+            match palette::pixel_coverage(&image, &color, color_filter.difference, color_filter.cover_ratio_from, color_filter.cover_ratio_to) {
+                Some(cover_ratio) => ret.push(cover_ratio),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(ret))
+    }).await?
+}
+
+// 在解密后的OCR文本、标题和备注中查找搜索词 不区分大小写 忽略FTS5语法中的引号
+fn do_text_filter(image: &Image, text: &Vec<String>) -> bool {
+    let mut content = vec![];
+    if let Some(ocr) = &image.ocr {
+        content.push(ocr.text().to_lowercase());
+    }
+    for v in [&image.title, &image.note].into_iter().flatten() {
+        content.push(v.to_lowercase());
+    }
+    text.iter().any(|v| {
+        let v = v.replace('"', "").trim().to_lowercase();
+        content.iter().any(|c| c.contains(v.as_str()))
+    })
+}
+
+async fn filter_image(image: &mut Image, request: &GetImageRequest) -> Result<bool> {
+    if let Some(text) = request.text.as_ref().filter(|text| text.len() > 0 && crypto::is_enabled()) {
+        if do_text_filter(image, text).not() {
             return Ok(false);
         }
     }
+    let color_filters = gen_color_filters(request);
+    if !color_filters.is_empty() && image.color_coverage.is_none() {
+        // 没有直方图 使用DeltaE计算颜色差异 计算颜色在图片中的比重
+        // 颜色差异在维基百科中的介绍：https://zh.wikipedia.org/wiki/%E9%A2%9C%E8%89%B2%E5%B7%AE%E5%BC%82
+        // 优先使用已经读取的缩略图 只返回缩略图时原图没有被读取
+        let data = match (&image.thumbnail, &image.image) {
+            (Some(data), _) | (None, Some(data)) => data.must_binary().clone(),
+            (None, None) => blob::get(&image.sum)?,
+        };
+        let color_filters = color_filters.into_iter().cloned().collect();
+        match do_color_filter(data, color_filters).await? {
+            Some(color_coverage) => image.color_coverage = Some(color_coverage),
+            // 覆盖率不在区间内会舍弃这个图片
+            None => return Ok(false),
+        }
+    }
     Ok(true)
 }
 
-fn get_image_inner(request: &GetImageRequest) -> Result<Vec<Image>> {
-    let client = client();
+// 每张图片返回的数据
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Content {
+    Original,
+    Thumbnail,
+    // 只返回元数据 由调用方按需读取原图
+    Metadata,
+}
+
+// 读取排在游标之后的limit张图片 同时返回每张图片的排序键
+fn get_image_inner(request: &GetImageRequest, content: Content, terms: &[SortTerm], cursor: &Option<Vec<CursorKey>>, limit: i64) -> Result<Vec<(Image, Vec<CursorKey>)>> {
+    let client = client()?;
     // 对请求做进一步处理
-    let limit = request.limit.or(Some(16)).unwrap();
+    let thumbnail_only = content == Content::Thumbnail;
+    // 按文本搜索时连接全文索引 用于排序和生成命中片段
+    let match_query = gen_match_query(request);
 
-    // 构造SQL
-    let mut sql = r#" SELECT id, image, ocr, size, width, height, ctime, mtime, sum FROM image WHERE 1 = 1 "#.to_string();
-    sql.push_str(gen_where_sql(request).as_str());
-    sql.push_str(" ORDER BY mtime DESC LIMIT :limit");
-    let mut stmt = client.prepare(sql.as_str())?;
-    let mut rows = stmt.query(named_params! {
-        ":limit": limit,
-    })?;
+    // 构造SQL 参数按照占位符出现的顺序绑定
+    let mut query = Query::new("SELECT id, ocr, size, width, height, ctime, mtime, sum,");
+    query.push("(SELECT COUNT(*) FROM image_occurrence AS o WHERE o.image_id = image.id),");
+    match &request.similar_to {
+        Some(similar_to) => query.append(&gen_distance(similar_to)),
+        None => query.push("NULL"),
+    };
+    query.push(if thumbnail_only { "AS distance, thumbnail," } else { "AS distance, NULL," });
+    query.push("pinned, image.title, image.note, deleted_at, use_count, last_used_at,");
+    match &match_query {
+        Some(_) => query.push("snippet(image_fts, -1, '<mark>', '</mark>', '...', 16)"),
+        None => query.push("NULL"),
+    };
+    // 排序键和颜色覆盖比例放在最后
+    for term in terms {
+        query.push(",").append(&term.expr);
+    }
+    let color_filters = gen_color_filters(request);
+    for color_filter in &color_filters {
+        query.push(",").append(&gen_coverage(color_filter));
+    }
+    match &match_query {
+        Some(text) => query.push_bind("FROM image, image_fts WHERE image_fts.rowid = image.id AND image_fts MATCH ?", [text.clone()]),
+        None => query.push("FROM image WHERE 1 = 1"),
+    };
+    query.append(&gen_where(request));
+    if let Some(cursor) = cursor {
+        query.append(&gen_after(terms, cursor));
+    }
+    query.push("ORDER BY");
+    for (i, term) in terms.iter().enumerate() {
+        if i > 0 {
+            query.push(",");
+        }
+        query.append(&term.expr).push(if term.desc { "DESC" } else { "ASC" });
+    }
+    query.push_bind("LIMIT ?", [limit]);
+    let mut stmt = client.prepare(query.sql())?;
+    let mut rows = stmt.query(query.params())?;
 
     // 构造返回值
     let mut ret = vec![];
+    let mut keys = vec![];
     while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        let sum: String = row.get(7)?;
+        let (image, thumbnail) = match content {
+            Content::Thumbnail => {
+                let thumbnail = match crypto::open_binary(row.get(10)?)? {
+                    Some(thumbnail) => thumbnail,
+                    // 还没有补充生成缩略图 临时生成
+                    None => generate_thumbnail(&sum)?,
+                };
+                (None, Some(ImageData::Binary(thumbnail)))
+            }
+            Content::Original => (Some(ImageData::Binary(blob::get(&sum)?)), None),
+            Content::Metadata => (None, None),
+        };
         ret.push(Image {
-            id: row.get(0)?,
-            image: ImageData::Binary(row.get(1)?),
-            ocr: match row.get::<usize, Option<String>>(2)? {
+            id,
+            image,
+            thumbnail,
+            ocr: match crypto::open_text(row.get(1)?)? {
                 None => None,
                 Some(ocr) => serde_json::from_str(ocr.as_str())?,
             },
-            size: row.get(3)?,
-            width: row.get(4)?,
-            height: row.get(5)?,
-            ctime: row.get(6)?,
-            mtime: row.get(7)?,
-            sum: row.get(8)?,
+            size: row.get(2)?,
+            width: row.get(3)?,
+            height: row.get(4)?,
+            ctime: row.get(5)?,
+            mtime: row.get(6)?,
+            sum,
+            occurrence: row.get(8)?,
+            distance: row.get(9)?,
+            snippet: row.get(17)?,
+            pinned: row.get(11)?,
+            tags: vec![],
+            title: crypto::open_text(row.get(12)?)?,
+            note: crypto::open_text(row.get(13)?)?,
+            deleted_at: row.get(14)?,
+            use_count: row.get(15)?,
+            last_used_at: row.get(16)?,
+            color_coverage: None,
         });
+        let mut key = vec![];
+        for i in 0..terms.len() {
+            key.push(CursorKey::from_value(row.get(18 + i)?)?);
+        }
+        keys.push(key);
+        // 有直方图时直接使用直方图计算的覆盖比例
+        if !color_filters.is_empty() {
+            let mut color_coverage = vec![];
+            for i in 0..color_filters.len() {
+                color_coverage.push(row.get::<_, Option<f64>>(18 + terms.len() + i)?);
+            }
+            ret.last_mut().unwrap().color_coverage = color_coverage.into_iter().collect();
+        }
+    }
+    // 补充图片的标签
+    let image_id: Vec<i64> = ret.iter().map(|image| image.id).collect();
+    let mut tags = image_tag::get_image_tag(&image_id)?;
+    for image in &mut ret {
+        image.tags = tags.remove(&image.id).unwrap_or_default();
+    }
+    Ok(ret.into_iter().zip(keys).collect())
+}
+
+// 临时生成缩略图 查询中不写数据库 由regular/backfill.rs保存
+fn generate_thumbnail(sum: &str) -> Result<Vec<u8>> {
+    let image = load_from_memory(blob::get(sum)?.as_slice())?.into_rgba8();
+    make_thumbnail(&image)
+}
+
+// 通过ID获取原图
+pub fn get_image_data(image_id: i64) -> Result<ImageData> {
+    let client = client()?;
+    let sum: Option<String> = client.query_row(
+        "SELECT sum FROM image WHERE id = :image_id",
+        named_params! { ":image_id": image_id },
+        |row| row.get(0),
+    ).optional()?;
+    match sum {
+        Some(sum) => Ok(ImageData::Binary(blob::get(&sum)?)),
+        None => bail!("no such image id: {}", image_id),
     }
-    Ok(ret)
 }
 
-pub async fn get_image(request: GetImageRequest) -> Result<Vec<Image>> {
-    let mut request = request;
+// 图片的出现记录 最近的在前
+pub fn get_image_occurrence(image_id: i64) -> Result<Vec<ImageOccurrence>> {
+    let client = client()?;
+    let mut stmt = client.prepare_cached(
+        "SELECT ctime, source FROM image_occurrence WHERE image_id = :image_id ORDER BY ctime DESC")?;
+    let mut rows = stmt.query(named_params! {
+        ":image_id": image_id,
+    })?;
     let mut ret = vec![];
-    if let Some(limit) = request.limit {
-        if limit <= 0 {
-            bail!("get_image error with limit <= 0");
-        }
+    while let Some(row) = rows.next()? {
+        ret.push(ImageOccurrence {
+            ctime: row.get(0)?,
+            source: row.get(1)?,
+        });
     }
-    if request.limit.is_none() {
-        request.limit = Some(16);
+    Ok(ret)
+}
+
+// 按游标翻页 返回的游标传入下一次请求 没有更多图片时游标为空
+pub async fn get_image(request: GetImageRequest) -> Result<ImagePage> {
+    let content = if request.thumbnail.is_some_and(|x| x) { Content::Thumbnail } else { Content::Original };
+    get_page(request, content).await
+}
+
+// 与get_image相同 但不读取原图和缩略图
+pub async fn get_image_metadata(request: GetImageRequest) -> Result<ImagePage> {
+    get_page(request, Content::Metadata).await
+}
+
+async fn get_page(request: GetImageRequest, content: Content) -> Result<ImagePage> {
+    let limit = request.limit.unwrap_or(16);
+    if limit <= 0 {
+        bail!("get_image error with limit <= 0");
     }
-    let source_limit = request.limit.unwrap();
+    let cursor = match &request.cursor {
+        Some(cursor) => Some(decode_cursor(cursor)?),
+        None => None,
+    };
+    // 第一页创建相关度快照 之后的页面沿用
+    let snapshot = match gen_rank_query(&request) {
+        Some(query) => Some(fts::snapshot_rank(&*client()?, &query, cursor.as_ref().and_then(|cursor| cursor.snapshot))?),
+        None => None,
+    };
+    let terms = gen_order(&request, snapshot);
+    let mut cursor = match cursor {
+        // 排序方式改变后游标失效
+        Some(cursor) if cursor.keys.len() != terms.len() => bail!("invalid cursor: {}", request.cursor.as_deref().unwrap_or_default()),
+        Some(cursor) => Some(cursor.keys),
+        None => None,
+    };
+    let mut ret = vec![];
+    // 需要在读取后过滤时 每次读取的数量翻倍 被过滤掉的图片在游标之前 不会重复读取
+    let mut batch = limit;
     loop {
-        let mut mtime: Option<i64> = None;
-        for image in get_image_inner(&request)? {
-            match mtime {
-                None => {
-                    mtime = Some(image.mtime.clone());
-                }
-                Some(t) => {
-                    mtime = Some(t.min(image.mtime.clone()));
-                }
-            }
-            if filter_image(&image, &request).await? {
+        let images = get_image_inner(&request, content, &terms, &cursor, batch)?;
+        let exhausted = (images.len() as i64) < batch;
+        for (mut image, key) in images {
+            cursor = Some(key);
+            if filter_image(&mut image, &request).await? {
                 ret.push(image);
-                if ret.len() as i64 >= source_limit {
-                    return Ok(ret);
+                if ret.len() as i64 >= limit {
+                    let cursor = encode_cursor(&Cursor { snapshot, keys: cursor.unwrap_or_default() })?;
+                    return Ok(ImagePage { images: ret, cursor: Some(cursor) });
                 }
             }
         }
-        if mtime.is_none() {
-            break;
+        if exhausted {
+            return Ok(ImagePage { images: ret, cursor: None });
         }
-        request.limit = Some(request.limit.unwrap() * 2);
-        request.mtime = mtime;
+        batch *= 2;
     }
-    Ok(ret)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+    use serde_json::json;
+    use crate::client::sqlite::testing;
+    use super::*;
+
+    fn request(value: serde_json::Value) -> GetImageRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    // 宽、高、大小、时间、置顶、删除时间、感知哈希、直方图、标题、最近使用时间
+    type SeedRow<'a> = (i32, i32, i64, i64, bool, Option<i64>, i64, Option<&'a [u8]>, Option<&'a str>, Option<i64>);
+
+    // 1: 横屏 红色 标签1、2 相似图片的目标
+    // 2: 竖屏 蓝色 标签1 置顶 在图集中 标题包含特殊字符
+    // 3: 方形 在回收站中
+    // 4: 横屏 没有直方图 在图集中 与1的感知哈希只差1位
+    fn seed() {
+        let client = client().unwrap();
+        let red = palette::make_palette(&RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+        let blue = palette::make_palette(&RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255])));
+        let rows: [SeedRow; 4] = [
+            (1920, 1080, 100, 10, false, None, 0, Some(red.as_slice()), Some("hello world"), None),
+            (1080, 1920, 3_000_000, 20, true, None, -1, Some(blue.as_slice()), Some(r#"100% off; it's "quoted" a_b"#), Some(5)),
+            (500, 500, 50, 30, false, Some(40), 0, None, None, None),
+            (1920, 1080, 200, 40, false, None, 1, None, None, Some(7)),
+        ];
+        for (i, (width, height, size, time, pinned, deleted_at, phash, palette, title, last_used_at)) in rows.into_iter().enumerate() {
+            client.execute(r#"INSERT INTO image (size, width, height, ctime, mtime, sum, pinned, deleted_at, phash, palette, title, last_used_at)
+                VALUES (?1, ?2, ?3, ?4, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"#,
+                (size, width, height, time, format!("{:064}", i), pinned, deleted_at, phash, palette, title, last_used_at)).unwrap();
+            fts::update(&client, i as i64 + 1).unwrap();
+        }
+        client.execute_batch(r#"
+        INSERT INTO tag (id, name, ctime) VALUES (1, 'a', 0), (2, 'b', 0);
+        INSERT INTO image_tag (image_id, tag_id) VALUES (1, 1), (1, 2), (2, 1);
+        INSERT INTO collection (id, name, position, ctime) VALUES (1, 'c', 0, 0);
+        INSERT INTO collection_image (collection_id, image_id, position) VALUES (1, 2, 0), (1, 4, 1);
+        "#).unwrap();
+    }
+
+    // 执行生成的条件 占位符数量与参数一致时才能执行
+    fn ids(condition: &Query) -> Vec<i64> {
+        let client = client().unwrap();
+        let mut query = Query::new("SELECT id FROM image WHERE 1 = 1");
+        query.append(condition).push("ORDER BY id");
+        let mut stmt = client.prepare(query.sql()).unwrap();
+        assert_eq!(query.sql().matches('?').count(), stmt.parameter_count(), "{}", query.sql());
+        let ids = stmt.query_map(query.params(), |row| row.get(0)).unwrap().collect::<rusqlite::Result<Vec<i64>>>().unwrap();
+        ids
+    }
+
+    #[test]
+    fn where_each_filter() {
+        let _lock = testing::reset();
+        seed();
+        let red = json!({"red": 255, "green": 0, "blue": 0, "cover_ratio_from": 0.5, "cover_ratio_to": 1.0, "difference": 10.0});
+        let cases = [
+            (json!({}), vec![1, 2, 4]),
+            (json!({"trash": true}), vec![3]),
+            (json!({"mtime": 30}), vec![1, 2]),
+            (json!({"id": []}), vec![1, 2, 4]),
+            (json!({"id": [1, 3, 1]}), vec![1]),
+            (json!({"text": ["hello"]}), vec![1]),
+            (json!({"text": ["hello", "quoted"]}), vec![1, 2]),
+            (json!({"date_range_from": 15, "date_range_to": 35}), vec![2]),
+            (json!({"width_from": 1920}), vec![1, 4]),
+            (json!({"width_to": 1920, "height_from": 1080, "height_to": 1080}), vec![1, 4]),
+            (json!({"size_from": 1000}), vec![2]),
+            (json!({"size_to": 150}), vec![1]),
+            (json!({"aspect_ratio_to": 1.0}), vec![2]),
+            (json!({"aspect_ratio_from": 1.7, "aspect_ratio_to": 1.8}), vec![1, 4]),
+            (json!({"orientation": "Landscape"}), vec![1, 4]),
+            (json!({"orientation": "Portrait"}), vec![2]),
+            (json!({"orientation": "Square", "trash": true}), vec![3]),
+            (json!({"tags": {"id": [], "mode": "ALL"}}), vec![1, 2, 4]),
+            (json!({"tags": {"id": [1, 1], "mode": "ANY"}}), vec![1, 2]),
+            (json!({"tags": {"id": [2, 1, 2], "mode": "ALL"}}), vec![1]),
+            (json!({"tags": {"id": [1, 1], "mode": "ALL"}}), vec![1, 2]),
+            (json!({"pinned": true}), vec![2]),
+            (json!({"pinned": false}), vec![1, 4]),
+            (json!({"collection_id": 1}), vec![2, 4]),
+            (json!({"similar_to": {"id": 1, "max_distance": 2}}), vec![4]),
+            // 没有直方图的图片在读取后按像素过滤
+            (json!({"color_filter": red}), vec![1, 4]),
+            (json!({"color_filters": [red, red]}), vec![1, 4]),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(ids(&gen_where(&request(value.clone()))), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn where_combined_filters() {
+        let _lock = testing::reset();
+        seed();
+        let cases = [
+            (json!({"tags": {"id": [1], "mode": "ALL"}, "pinned": false, "orientation": "Landscape"}), vec![1]),
+            (json!({"text": ["hello"], "width_from": 1000, "date_range_from": 5, "date_range_to": 15}), vec![1]),
+            (json!({"collection_id": 1, "size_from": 150, "tags": {"id": [1], "mode": "ANY"}}), vec![2]),
+            (json!({"trash": true, "collection_id": 1}), vec![]),
+            (json!({"similar_to": {"id": 1, "max_distance": 64}, "pinned": true, "text": ["quoted"]}), vec![2]),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(ids(&gen_where(&request(value.clone()))), expected, "{}", value);
+        }
+    }
+
+    // 搜索词中的引号、通配符和分号只作为文本匹配
+    #[test]
+    fn where_hostile_text() {
+        let _lock = testing::reset();
+        seed();
+        let cases = [
+            ("'; DROP TABLE image; --", vec![]),
+            ("\"quoted\"", vec![2]),
+            ("it's", vec![2]),
+            ("100%", vec![2]),
+            ("%", vec![2]),
+            ("_", vec![2]),
+            ("a_b", vec![2]),
+            ("off;", vec![2]),
+            ("?", vec![]),
+            ("\"", vec![2]),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(ids(&gen_where(&request(json!({"text": [text]})))), expected, "{}", text);
+        }
+        assert_eq!(ids(&gen_where(&request(json!({})))), vec![1, 2, 4]);
+    }
+
+    // 游标之后的图片 NULL排在最小的位置
+    #[test]
+    fn after_cursor() {
+        let _lock = testing::reset();
+        seed();
+        let cases = [
+            ("ASC", vec![CursorKey::Null, CursorKey::Integer(1)], vec![2, 4]),
+            ("ASC", vec![CursorKey::Integer(5), CursorKey::Integer(2)], vec![4]),
+            ("ASC", vec![CursorKey::Integer(7), CursorKey::Integer(4)], vec![]),
+            ("DESC", vec![CursorKey::Integer(7), CursorKey::Integer(4)], vec![1, 2]),
+            ("DESC", vec![CursorKey::Integer(5), CursorKey::Integer(2)], vec![1]),
+            ("DESC", vec![CursorKey::Null, CursorKey::Integer(1)], vec![]),
+        ];
+        for (direction, keys, expected) in cases {
+            let request = request(json!({"sort": {"field": "LastUsed", "direction": direction}}));
+            let terms = gen_order(&request, None);
+            let mut condition = gen_where(&request);
+            condition.append(&gen_after(&terms, &keys));
+            assert_eq!(ids(&condition), expected, "{} {:?}", direction, keys);
+        }
+        // 不能为NULL的排序键额外使用范围条件
+        let request = request(json!({"sort": {"field": "Size", "direction": "DESC"}}));
+        let mut condition = gen_where(&request);
+        condition.append(&gen_after(&gen_order(&request, None), &[CursorKey::Integer(200), CursorKey::Integer(4)]));
+        assert_eq!(ids(&condition), vec![1]);
+    }
+
+    // 排序键大量相同的图片 标题都包含hello
+    fn add(id: i64, time: i64, size: i64, use_count: i64, last_used_at: Option<i64>, title: &str) {
+        let client = client().unwrap();
+        let sum = format!("{:064}", id);
+        blob::put(&sum, b"image").unwrap();
+        client.execute(r#"INSERT INTO image (id, size, width, height, ctime, mtime, sum, use_count, last_used_at, title)
+            VALUES (?1, ?2, 1, 1, ?3, ?3, ?4, ?5, ?6, ?7)"#, (id, size, time, &sum, use_count, last_used_at, title)).unwrap();
+        fts::update(&client, id).unwrap();
+    }
+
+    fn page(runtime: &tokio::runtime::Runtime, request: &serde_json::Value, cursor: &Option<String>) -> ImagePage {
+        let mut request = request.clone();
+        request["limit"] = json!(3);
+        request["cursor"] = json!(cursor);
+        runtime.block_on(get_image(self::request(request))).unwrap()
+    }
+
+    // 翻页期间插入图片 已有的图片不重复也不遗漏
+    #[test]
+    fn page_with_ties_and_inserts() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut requests = vec![json!({}), json!({"text": ["hello"]})];
+        for field in ["Ctime", "Mtime", "Size", "UseCount", "LastUsed"] {
+            for direction in ["ASC", "DESC"] {
+                requests.push(json!({"sort": {"field": field, "direction": direction}}));
+            }
+        }
+        for request in requests {
+            let _lock = testing::reset();
+            for id in 1..=10 {
+                add(id, 100 + id % 2, 10 * (id % 3), id % 2, if id % 4 == 0 { None } else { Some(5) }, &format!("hello {}", "x".repeat(id as usize)));
+            }
+            let mut seen = vec![];
+            let mut cursor = None;
+            for i in 0.. {
+                let page = page(&runtime, &request, &cursor);
+                seen.extend(page.images.iter().map(|image| image.id));
+                cursor = page.cursor;
+                if cursor.is_none() {
+                    break;
+                }
+                // 插入排序键相同的图片 文本搜索时同时改变BM25的统计信息
+                add(100 + i, 101, 10, 1, Some(5), &"hello ".repeat(i as usize + 1));
+                add(200 + i, 100, 0, 0, None, "hello");
+            }
+            let mut unique = seen.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), seen.len(), "{} {:?}", request, seen);
+            for id in 1..=10 {
+                assert!(seen.contains(&id), "{} {:?}", request, seen);
+            }
+        }
+    }
+}
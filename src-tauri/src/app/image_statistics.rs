@@ -0,0 +1,96 @@
+use std::fs;
+use anyhow::Result;
+use rusqlite::{Connection, named_params};
+use crate::app::image_clean;
+use crate::client::blob;
+use crate::client::sqlite::{client, get_database_path};
+use crate::model::{LargestImage, Statistics, StatisticsBucket};
+
+// 最大图片的数量
+const LARGEST_LIMIT: i64 = 10;
+// 识别成功和没有识别到文本 其他结果视为失败
+const OCR_DONE_CODES: &str = "(100, 101)";
+
+fn get_buckets(client: &Connection, sql: &str) -> Result<Vec<StatisticsBucket>> {
+    let mut stmt = client.prepare(sql)?;
+    let buckets = stmt.query_map(named_params! {}, |row| Ok(StatisticsBucket {
+        key: row.get(0)?,
+        count: row.get(1)?,
+        bytes: row.get(2)?,
+    }))?.collect::<rusqlite::Result<Vec<StatisticsBucket>>>()?;
+    Ok(buckets)
+}
+
+// 数据库文件和WAL文件的大小
+fn get_database_file_bytes() -> Result<i64> {
+    let path = get_database_path();
+    let mut bytes = fs::metadata(path)?.len();
+    if let Ok(wal) = fs::metadata(format!("{}-wal", path)) {
+        bytes += wal.len();
+    }
+    Ok(bytes as i64)
+}
+
+// 存储和使用情况的统计 只使用SQL聚合 不读取图片
+pub fn get_statistics() -> Result<Statistics> {
+    let client = client()?;
+    let (count, bytes, trash_count, trash_bytes): (i64, i64, i64, i64) = client.query_row(r#"SELECT
+        COUNT(*) FILTER (WHERE deleted_at IS NULL), IFNULL(SUM(size) FILTER (WHERE deleted_at IS NULL), 0),
+        COUNT(*) FILTER (WHERE deleted_at IS NOT NULL), IFNULL(SUM(size) FILTER (WHERE deleted_at IS NOT NULL), 0)
+        FROM image"#, (), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+    let daily = get_buckets(&client, r#"SELECT DATE(ctime / 1000, 'unixepoch', 'localtime') AS day, COUNT(*), SUM(size)
+        FROM image WHERE deleted_at IS NULL GROUP BY day ORDER BY day"#)?;
+    let monthly = get_buckets(&client, r#"SELECT STRFTIME('%Y-%m', ctime / 1000, 'unixepoch', 'localtime') AS month, COUNT(*), SUM(size)
+        FROM image WHERE deleted_at IS NULL GROUP BY month ORDER BY month"#)?;
+    // 按数量级划分大小区间 没有图片的区间不返回
+    let histogram = get_buckets(&client, r#"SELECT CASE
+            WHEN size < 10 * 1024 THEN '<10KB'
+            WHEN size < 100 * 1024 THEN '10KB-100KB'
+            WHEN size < 1024 * 1024 THEN '100KB-1MB'
+            WHEN size < 10 * 1024 * 1024 THEN '1MB-10MB'
+            ELSE '>=10MB' END AS bucket,
+        COUNT(*), SUM(size)
+        FROM image WHERE deleted_at IS NULL GROUP BY bucket ORDER BY MIN(size)"#)?;
+    let largest = {
+        let mut stmt = client.prepare(r#"SELECT id, size, width, height, ctime FROM image
+            WHERE deleted_at IS NULL ORDER BY size DESC LIMIT ?1"#)?;
+        let largest = stmt.query_map((LARGEST_LIMIT,), |row| Ok(LargestImage {
+            id: row.get(0)?,
+            size: row.get(1)?,
+            width: row.get(2)?,
+            height: row.get(3)?,
+            ctime: row.get(4)?,
+        }))?.collect::<rusqlite::Result<Vec<LargestImage>>>()?;
+        largest
+    };
+    // 加密后的识别结果无法在SQL中读取 视为已完成
+    let (ocr_done, ocr_pending, ocr_failed): (i64, i64, i64) = client.query_row(format!(r#"SELECT
+        COUNT(*) FILTER (WHERE TYPEOF(ocr) = 'blob' OR (TYPEOF(ocr) = 'text' AND JSON_EXTRACT(ocr, '$.code') IN {0})),
+        COUNT(*) FILTER (WHERE ocr IS NULL),
+        COUNT(*) FILTER (WHERE TYPEOF(ocr) = 'text' AND JSON_EXTRACT(ocr, '$.code') NOT IN {0})
+        FROM image WHERE deleted_at IS NULL"#, OCR_DONE_CODES).as_str(), (), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    // 同一张图片再次复制时只增加出现记录 不重复保存
+    let (duplicate_count, duplicate_bytes): (i64, i64) = client.query_row(r#"SELECT
+        IFNULL(SUM(o.count - 1), 0), IFNULL(SUM((o.count - 1) * image.size), 0)
+        FROM image JOIN (SELECT image_id, COUNT(*) AS count FROM image_occurrence GROUP BY image_id) AS o
+        ON o.image_id = image.id WHERE image.deleted_at IS NULL AND o.count > 1"#,
+        (), |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(Statistics {
+        count,
+        bytes,
+        trash_count,
+        trash_bytes,
+        daily,
+        monthly,
+        histogram,
+        largest,
+        ocr_done,
+        ocr_pending,
+        ocr_failed,
+        duplicate_count,
+        duplicate_bytes,
+        database_file_bytes: get_database_file_bytes()?,
+        database_live_bytes: image_clean::get_database_used_bytes()?,
+        blob_bytes: blob::usage()? as i64,
+    })
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use anyhow::{bail, Result};
+use rusqlite::{named_params, TransactionBehavior};
+use crate::client::query::Query;
+use crate::client::sqlite::client;
+use crate::model::Tag;
+
+fn check_name(name: &str) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        bail!("tag name can not be empty");
+    }
+    Ok(name.to_string())
+}
+
+// 创建标签
+pub fn create_tag(name: &str) -> Result<Tag> {
+    let name = check_name(name)?;
+    let client = client()?;
+    let now = chrono::Local::now().timestamp_millis();
+    client.execute("INSERT INTO tag (name, ctime) VALUES (?1, ?2)", (&name, &now))?;
+    Ok(Tag {
+        id: client.last_insert_rowid(),
+        name,
+    })
+}
+
+// 重命名标签
+pub fn rename_tag(tag_id: i64, name: &str) -> Result<()> {
+    let name = check_name(name)?;
+    let client = client()?;
+    if client.execute("UPDATE tag SET name = ?2 WHERE id = ?1", (&tag_id, &name))? == 0 {
+        bail!("no such tag id: {}", tag_id);
+    }
+    Ok(())
+}
+
+// 删除标签 图片与标签的关联一起删除
+pub fn delete_tag(tag_id: &Vec<i64>) -> Result<()> {
+    let client = client()?;
+    let mut query = Query::new("DELETE FROM tag WHERE id IN");
+    query.push_list(tag_id.iter().cloned());
+    client.execute(query.sql(), query.params())?;
+    Ok(())
+}
+
+// 全部标签 按名称排序
+pub fn get_tag() -> Result<Vec<Tag>> {
+    let client = client()?;
+    let mut stmt = client.prepare_cached("SELECT id, name FROM tag ORDER BY name")?;
+    let mut rows = stmt.query(named_params! {})?;
+    let mut ret = vec![];
+    while let Some(row) = rows.next()? {
+        ret.push(Tag {
+            id: row.get(0)?,
+            name: row.get(1)?,
+        });
+    }
+    Ok(ret)
+}
+
+// 给多张图片添加多个标签 已经添加过的忽略
+pub fn assign_tag(image_id: &Vec<i64>, tag_id: &Vec<i64>) -> Result<()> {
+    let mut client = client()?;
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    {
+        let mut stmt = tx.prepare_cached("INSERT OR IGNORE INTO image_tag (image_id, tag_id) VALUES (?1, ?2)")?;
+        for image_id in image_id {
+            for tag_id in tag_id {
+                stmt.execute((image_id, tag_id))?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// 移除多张图片的多个标签
+pub fn unassign_tag(image_id: &Vec<i64>, tag_id: &Vec<i64>) -> Result<()> {
+    let client = client()?;
+    let mut query = Query::new("DELETE FROM image_tag WHERE image_id IN");
+    query.push_list(image_id.iter().cloned());
+    query.push("AND tag_id IN").push_list(tag_id.iter().cloned());
+    client.execute(query.sql(), query.params())?;
+    Ok(())
+}
+
+// 批量查询图片的标签 返回图片ID到标签列表的映射
+pub fn get_image_tag(image_id: &Vec<i64>) -> Result<HashMap<i64, Vec<Tag>>> {
+    let client = client()?;
+    let mut query = Query::new(r#"SELECT image_tag.image_id, tag.id, tag.name
+        FROM image_tag JOIN tag ON tag.id = image_tag.tag_id WHERE image_tag.image_id IN"#);
+    query.push_list(image_id.iter().cloned()).push("ORDER BY tag.name");
+    let mut stmt = client.prepare(query.sql())?;
+    let mut rows = stmt.query(query.params())?;
+    let mut ret: HashMap<i64, Vec<Tag>> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        ret.entry(row.get(0)?).or_default().push(Tag {
+            id: row.get(1)?,
+            name: row.get(2)?,
+        });
+    }
+    Ok(ret)
+}
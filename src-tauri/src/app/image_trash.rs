@@ -0,0 +1,67 @@
+use anyhow::Result;
+use rusqlite::TransactionBehavior;
+use crate::client::blob;
+use crate::client::query::Query;
+use crate::client::sqlite::client;
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+// 移入回收站 图片文件保留 可以恢复
+pub fn trash_image(image_id: &Vec<i64>) -> Result<()> {
+    let client = client()?;
+    let now = chrono::Local::now().timestamp_millis();
+    let mut query = Query::default();
+    query.push_bind("UPDATE image SET deleted_at = ? WHERE deleted_at IS NULL AND id IN", [now])
+        .push_list(image_id.iter().cloned());
+    client.execute(query.sql(), query.params())?;
+    Ok(())
+}
+
+pub fn restore_image(image_id: &Vec<i64>) -> Result<()> {
+    let client = client()?;
+    let mut query = Query::new("UPDATE image SET deleted_at = NULL WHERE id IN");
+    query.push_list(image_id.iter().cloned());
+    client.execute(query.sql(), query.params())?;
+    Ok(())
+}
+
+// 彻底删除回收站中满足条件的图片 返回删除的数量
+fn purge(condition: &Query) -> Result<usize> {
+    let mut client = client()?;
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let mut query = Query::new("SELECT sum FROM image WHERE deleted_at IS NOT NULL");
+    query.append(condition);
+    let sums = {
+        let mut stmt = tx.prepare(query.sql())?;
+        let sums = stmt.query_map(query.params(), |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+        sums
+    };
+    let mut query = Query::new("DELETE FROM image WHERE deleted_at IS NOT NULL");
+    query.append(condition);
+    let count = tx.execute(query.sql(), query.params())?;
+    tx.commit()?;
+    // 删除不再被引用的图片文件
+    blob::remove_orphans(&mut client, &sums)?;
+    // 归还删除后产生的空闲页 缩小数据库文件
+    if count > 0 {
+        client.execute_batch("PRAGMA incremental_vacuum")?;
+    }
+    Ok(count)
+}
+
+// 没有指定ID时清空整个回收站
+pub fn purge_trash(image_id: &Option<Vec<i64>>) -> Result<usize> {
+    let mut condition = Query::default();
+    if let Some(image_id) = image_id {
+        condition.push("AND id IN").push_list(image_id.iter().cloned());
+    }
+    purge(&condition)
+}
+
+// 彻底删除在回收站中超过保留天数的图片
+pub fn purge_expired(retention_days: i64) -> Result<usize> {
+    let before = chrono::Local::now().timestamp_millis() - retention_days.max(0) * DAY_MILLIS;
+    let mut condition = Query::default();
+    condition.push_bind("AND deleted_at <= ?", [before]);
+    purge(&condition)
+}
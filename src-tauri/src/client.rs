@@ -1 +1,7 @@
-pub mod sqlite;
\ No newline at end of file
+pub mod backup;
+pub mod blob;
+pub mod crypto;
+pub mod fts;
+pub mod migration;
+pub mod query;
+pub mod sqlite;
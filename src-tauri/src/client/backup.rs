@@ -0,0 +1,199 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{bail, Result};
+use log::info;
+use once_cell::sync::Lazy;
+use rusqlite::backup::{Backup as SqliteBackup, StepResult};
+use rusqlite::{Connection, named_params, OpenFlags};
+use crate::client::{blob, crypto, migration};
+use crate::client::sqlite::{client, reset_pool};
+use crate::common::get_root;
+use crate::model::Backup;
+
+// 每个备份一个目录 目录名为备份时间
+static BACKUP_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    let root = get_root();
+    root.join("backup")
+});
+
+const DATABASE: &str = "database.sqlite3";
+// 备份引用的图片文件 使用硬链接保存 不额外占用空间
+const BLOB: &str = "blob";
+// 数据库被锁定时的重试次数和间隔
+const RETRY: usize = 100;
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+fn get_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_digit() || c == '-') {
+        bail!("invalid backup name: {}", name);
+    }
+    Ok(BACKUP_PATH.join(name))
+}
+
+// 优先使用硬链接 不支持时复制文件
+fn link(from: &Path, to: &Path) -> Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+// 一次复制全部页面 WAL模式下不会阻塞其他连接写入 数据库被锁定时等待后重试
+pub fn copy(from: &Connection, to: &mut Connection) -> Result<()> {
+    let backup = SqliteBackup::new(from, to)?;
+    for _ in 0..RETRY {
+        if let StepResult::Done = backup.step(-1)? {
+            return Ok(());
+        }
+        std::thread::sleep(RETRY_INTERVAL);
+    }
+    bail!("database is busy, backup timeout")
+}
+
+// 使用SQLite的在线备份接口创建数据库快照 同时保存快照引用的图片文件
+pub fn create() -> Result<Backup> {
+    let name = chrono::Local::now().format("%Y%m%d-%H%M%S-%3f").to_string();
+    let path = get_path(&name)?;
+    let blob_path = path.join(BLOB);
+    fs::create_dir_all(blob_path.as_path())?;
+    let result = (|| -> Result<()> {
+        let client = client()?;
+        let mut snapshot = Connection::open(path.join(DATABASE))?;
+        copy(&client, &mut snapshot)?;
+        let mut stmt = snapshot.prepare("SELECT DISTINCT sum FROM image")?;
+        let mut rows = stmt.query(named_params! {})?;
+        while let Some(row) = rows.next()? {
+            let sum: String = row.get(0)?;
+            let from = blob::get_path(&sum)?;
+            // 图片可能已经被删除 快照中的记录仍然保留
+            if from.is_file() {
+                link(from.as_path(), blob_path.join(format!("{}.png", sum)).as_path())?;
+            }
+        }
+        Ok(())
+    })();
+    if let Err(err) = result {
+        // 不保留不完整的备份
+        fs::remove_dir_all(path.as_path())?;
+        return Err(err);
+    }
+    info!("database backup created: {}", name);
+    get(&name)
+}
+
+fn get(name: &str) -> Result<Backup> {
+    let metadata = fs::metadata(get_path(name)?.join(DATABASE))?;
+    let ctime: chrono::DateTime<chrono::Local> = metadata.modified()?.into();
+    Ok(Backup {
+        name: name.to_string(),
+        ctime: ctime.timestamp_millis(),
+        size: metadata.len() as i64,
+    })
+}
+
+// 全部备份 最新的在前
+pub fn list() -> Result<Vec<Backup>> {
+    if !BACKUP_PATH.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut names = vec![];
+    for dir in fs::read_dir(BACKUP_PATH.as_path())? {
+        let dir = dir?.path();
+        if let Some(name) = dir.file_name().and_then(|name| name.to_str()) {
+            if get_path(name).is_ok() && dir.join(DATABASE).is_file() {
+                names.push(name.to_string());
+            }
+        }
+    }
+    // 目录名为备份时间 可以直接按名称排序
+    names.sort_by(|a, b| b.cmp(a));
+    names.iter().map(|name| get(name)).collect()
+}
+
+// 只保留最新的retention个备份 返回删除的备份数量
+pub fn rotate(retention: usize) -> Result<usize> {
+    let backups = list()?;
+    let mut count = 0;
+    for backup in backups.iter().skip(retention) {
+        fs::remove_dir_all(get_path(&backup.name)?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// 删除全部备份 返回删除的备份数量
+// 开启加密或修改口令后 已有的备份中仍是明文或者使用旧口令加密的数据
+pub fn remove_all() -> Result<usize> {
+    let backups = list()?;
+    for backup in backups.iter() {
+        fs::remove_dir_all(get_path(&backup.name)?)?;
+    }
+    Ok(backups.len())
+}
+
+// 用备份替换当前数据库 调用方需要先暂停后台任务和剪切板写入
+pub fn restore(name: &str) -> Result<()> {
+    let path = get_path(name)?;
+    let snapshot = Connection::open_with_flags(path.join(DATABASE), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let check: String = snapshot.query_row("PRAGMA quick_check", (), |row| row.get(0))?;
+    if check != "ok" {
+        bail!("backup {} is damaged: {}", name, check);
+    }
+    if migration::get_version(&snapshot)? > migration::latest_version() {
+        bail!("backup {} is created by a newer version", name);
+    }
+    // 先放回图片文件 恢复后的记录不会引用不存在的图片
+    let blob_path = path.join(BLOB);
+    if blob_path.is_dir() {
+        for file in fs::read_dir(blob_path.as_path())? {
+            let file = file?.path();
+            if let Some(sum) = file.file_stem().and_then(|stem| stem.to_str()) {
+                let to = blob::get_path(sum)?;
+                if !to.is_file() {
+                    fs::create_dir_all(to.parent().unwrap())?;
+                    link(file.as_path(), to.as_path())?;
+                }
+            }
+        }
+    }
+    {
+        let mut client = client()?;
+        copy(&snapshot, &mut client)?;
+        migration::migrate(&mut client)?;
+        // 备份中的加密配置可能不同 需要重新解锁
+        crypto::load(&client)?;
+        blob::gc(&client)?;
+    }
+    reset_pool();
+    info!("database restored from backup: {}", name);
+    Ok(())
+}
+
+// 最近一次备份的时间
+pub fn latest_ctime() -> Result<Option<i64>> {
+    Ok(list()?.first().map(|backup| backup.ctime))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::sqlite::testing;
+    use super::*;
+
+    // 开启加密或修改口令后不保留任何旧的备份
+    #[test]
+    fn remove_all_backups() {
+        let _lock = testing::reset();
+        let sum = sha256::digest("plain");
+        client().unwrap().execute("INSERT INTO image (size, width, height, ctime, mtime, sum) VALUES (1, 1, 1, 1, 1, ?1)", (&sum,)).unwrap();
+        blob::put(&sum, b"plain").unwrap();
+        create().unwrap();
+        // 备份以毫秒时间命名
+        std::thread::sleep(Duration::from_millis(2));
+        create().unwrap();
+        assert!(list().unwrap().len() >= 2);
+        assert!(remove_all().unwrap() >= 2);
+        assert!(list().unwrap().is_empty());
+        assert_eq!(remove_all().unwrap(), 0);
+    }
+}
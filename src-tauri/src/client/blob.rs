@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+use log::info;
+use once_cell::sync::Lazy;
+use rusqlite::{Connection, named_params, TransactionBehavior};
+use crate::client::crypto;
+use crate::common::get_root;
+
+// 按内容寻址的图片存储 文件名即为图片的sum
+static BLOB_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    let root = get_root();
+    root.join("blob")
+});
+
+fn check_sum(sum: &str) -> Result<()> {
+    if sum.len() != 64 || !sum.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("invalid blob sum: {}", sum);
+    }
+    Ok(())
+}
+
+// 使用sum的前两位作为子目录 避免单个目录下文件过多
+pub fn get_path(sum: &str) -> Result<PathBuf> {
+    check_sum(sum)?;
+    Ok(BLOB_PATH.join(&sum[..2]).join(format!("{}.png", sum)))
+}
+
+// 先写临时文件再重命名 避免中途失败留下不完整的图片
+fn write(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(tmp.as_path(), data)?;
+    fs::rename(tmp.as_path(), path)?;
+    Ok(())
+}
+
+// 写入图片 相同sum的图片只会保存一份 开启加密时保存加密后的数据
+pub fn put(sum: &str, data: &[u8]) -> Result<()> {
+    let path = get_path(sum)?;
+    if path.is_file() {
+        return Ok(());
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+    write(path.as_path(), crypto::seal(data)?.as_slice())
+}
+
+// 读取图片 返回解密后的数据
+pub fn get(sum: &str) -> Result<Vec<u8>> {
+    crypto::open(fs::read(get_path(sum)?)?)
+}
+
+fn is_referenced(client: &Connection, sum: &str) -> Result<bool> {
+    let mut stmt = client.prepare_cached("SELECT 1 FROM image WHERE sum = :sum LIMIT 1")?;
+    let mut rows = stmt.query(named_params! {
+        ":sum": sum,
+    })?;
+    Ok(rows.next()?.is_some())
+}
+
+// 删除数据库中已经没有记录引用的图片 在删除记录后调用
+// 插入图片时在写事务中先写文件再插入记录 检查期间持有写锁 不会删除正在插入的图片
+pub fn remove_orphans(client: &mut Connection, sums: &[String]) -> Result<()> {
+    let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    for sum in sums {
+        if is_referenced(&tx, sum)? {
+            continue;
+        }
+        let path = get_path(sum)?;
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// 扫描整个存储目录 删除所有没有被引用的文件 返回删除的文件数量
+pub fn gc(client: &Connection) -> Result<usize> {
+    if !BLOB_PATH.is_dir() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    for dir in fs::read_dir(BLOB_PATH.as_path())? {
+        let dir = dir?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(dir.as_path())? {
+            let file = file?.path();
+            let orphan = match (file.extension(), file.file_stem()) {
+                (Some(ext), Some(stem)) if ext == "png" => {
+                    let sum = stem.to_string_lossy();
+                    check_sum(&sum).is_err() || !is_referenced(client, &sum)?
+                }
+                // 写入中途失败留下的临时文件
+                _ => true,
+            };
+            if orphan {
+                fs::remove_file(file.as_path())?;
+                count += 1;
+            }
+        }
+    }
+    if count > 0 {
+        info!("blob gc removed {} files", count);
+    }
+    Ok(count)
+}
+
+// 把没有使用当前密钥加密的图片重新加密 返回重新加密的文件数量
+pub fn reseal() -> Result<usize> {
+    if !BLOB_PATH.is_dir() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    for dir in fs::read_dir(BLOB_PATH.as_path())? {
+        let dir = dir?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(dir.as_path())? {
+            let file = file?.path();
+            if file.extension().is_some_and(|ext| ext == "png") {
+                let data = fs::read(file.as_path())?;
+                if crypto::need_reseal(&data) {
+                    write(file.as_path(), crypto::seal(crypto::open(data)?.as_slice())?.as_slice())?;
+                    count += 1;
+                }
+            }
+        }
+    }
+    Ok(count)
+}
+
+// 存储目录占用的字节数
+pub fn usage() -> Result<u64> {
+    if !BLOB_PATH.is_dir() {
+        return Ok(0);
+    }
+    let mut total = 0;
+    for dir in fs::read_dir(BLOB_PATH.as_path())? {
+        let dir = dir?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(dir.as_path())? {
+            total += file?.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
@@ -0,0 +1,497 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::aead::rand_core::RngCore;
+use anyhow::{anyhow, bail, Result};
+use argon2::Argon2;
+use log::info;
+use once_cell::sync::Lazy;
+use rusqlite::{Connection, named_params, OptionalExtension, TransactionBehavior};
+use rusqlite::types::Value;
+use crate::client::blob;
+use crate::client::sqlite::client;
+
+// 加密数据的格式：MAGIC + 密钥ID + nonce + 密文
+const MAGIC: &[u8; 4] = b"WCE1";
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_LEN;
+const SALT_LEN: usize = 16;
+// 每次从数据库中取出重新加密的行数
+const BATCH: i64 = 16;
+// 需要加密的文本和缩略图列
+const COLUMNS: [&str; 4] = ["ocr", "title", "note", "thumbnail"];
+
+type KeyId = [u8; KEY_ID_LEN];
+
+// 数据密钥 图片和文本都使用current加密 旧的密钥只用于解密还没有重新加密的数据
+struct Keys {
+    current: KeyId,
+    all: HashMap<KeyId, Aes256Gcm>,
+}
+
+#[derive(Default)]
+struct State {
+    enabled: bool,
+    keys: Option<Keys>,
+}
+
+static STATE: Lazy<RwLock<State>> = Lazy::new(|| {
+    RwLock::new(State::default())
+});
+
+// 使用Argon2从口令派生出用于加密数据密钥的密钥
+fn derive(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("derive key failed: {}", err))?;
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut data = [0u8; N];
+    OsRng.fill_bytes(&mut data);
+    data
+}
+
+fn encrypt(cipher: &Aes256Gcm, key_id: &KeyId, data: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let encrypted = cipher.encrypt(&nonce, data).map_err(|_| anyhow!("encrypt failed"))?;
+    let mut ret = Vec::with_capacity(HEADER_LEN + encrypted.len());
+    ret.extend_from_slice(MAGIC);
+    ret.extend_from_slice(key_id);
+    ret.extend_from_slice(nonce.as_slice());
+    ret.extend_from_slice(encrypted.as_slice());
+    Ok(ret)
+}
+
+fn get_key_id(data: &[u8]) -> Option<KeyId> {
+    if data.len() < HEADER_LEN || !data.starts_with(MAGIC) {
+        return None;
+    }
+    data[MAGIC.len()..MAGIC.len() + KEY_ID_LEN].try_into().ok()
+}
+
+fn decrypt(cipher: &Aes256Gcm, data: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Nonce::from_slice(&data[MAGIC.len() + KEY_ID_LEN..HEADER_LEN]);
+    cipher.decrypt(nonce, &data[HEADER_LEN..]).map_err(|_| anyhow!("decrypt failed"))
+}
+
+pub fn is_enabled() -> bool {
+    STATE.read().unwrap().enabled
+}
+
+// 开启了加密但还没有输入口令
+pub fn is_locked() -> bool {
+    let state = STATE.read().unwrap();
+    state.enabled && state.keys.is_none()
+}
+
+pub fn check_unlocked() -> Result<()> {
+    if is_locked() {
+        bail!("image history is encrypted and locked");
+    }
+    Ok(())
+}
+
+// 开启加密时加密数据 否则原样返回
+pub fn seal(data: &[u8]) -> Result<Vec<u8>> {
+    let state = STATE.read().unwrap();
+    if !state.enabled {
+        return Ok(data.to_vec());
+    }
+    match &state.keys {
+        Some(keys) => encrypt(&keys.all[&keys.current], &keys.current, data),
+        None => bail!("image history is encrypted and locked"),
+    }
+}
+
+// 解密数据 没有加密的数据原样返回
+pub fn open(data: Vec<u8>) -> Result<Vec<u8>> {
+    let key_id = match get_key_id(&data) {
+        Some(key_id) => key_id,
+        None => return Ok(data),
+    };
+    let state = STATE.read().unwrap();
+    match &state.keys {
+        Some(keys) => match keys.all.get(&key_id) {
+            Some(cipher) => decrypt(cipher, &data),
+            None => bail!("data is encrypted with an unknown key"),
+        },
+        None => bail!("image history is encrypted and locked"),
+    }
+}
+
+// 开启加密时文本以加密后的BLOB保存
+pub fn seal_text(text: Option<&str>) -> Result<Value> {
+    Ok(match text {
+        None => Value::Null,
+        Some(text) if is_enabled() => Value::Blob(seal(text.as_bytes())?),
+        Some(text) => Value::Text(text.to_string()),
+    })
+}
+
+pub fn open_text(value: Value) -> Result<Option<String>> {
+    Ok(match value {
+        Value::Null => None,
+        Value::Text(text) => Some(text),
+        Value::Blob(data) => Some(String::from_utf8(open(data)?)?),
+        _ => bail!("invalid encrypted text"),
+    })
+}
+
+pub fn open_binary(value: Value) -> Result<Option<Vec<u8>>> {
+    Ok(match value {
+        Value::Null => None,
+        Value::Blob(data) => Some(open(data)?),
+        _ => bail!("invalid encrypted binary"),
+    })
+}
+
+// 数据没有使用当前密钥加密 需要重新加密
+pub fn need_reseal(data: &[u8]) -> bool {
+    let state = STATE.read().unwrap();
+    match &state.keys {
+        Some(keys) if state.enabled => get_key_id(data) != Some(keys.current),
+        _ => false,
+    }
+}
+
+// 启动或恢复备份时读取是否开启了加密 开启时需要重新解锁
+// 还没有完成重新加密时 解锁后继续
+pub fn load(client: &Connection) -> Result<()> {
+    let enabled = client.query_row("SELECT 1 FROM encryption WHERE id = 1", (), |_| Ok(())).optional()?.is_some();
+    if is_reseal_pending(client)? {
+        info!("encryption reseal is pending, resume after unlock");
+    }
+    let mut state = STATE.write().unwrap();
+    state.enabled = enabled;
+    state.keys = None;
+    Ok(())
+}
+
+// 测试之间不共享加密状态
+#[cfg(test)]
+pub fn reset() {
+    *STATE.write().unwrap_or_else(|err| err.into_inner()) = State::default();
+}
+
+fn to_keys(current: KeyId, raw: &HashMap<KeyId, Vec<u8>>) -> Keys {
+    let all = raw.iter().map(|(id, key)| (*id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))).collect();
+    Keys { current, all }
+}
+
+// 用口令解开全部数据密钥 口令错误时返回错误
+fn unwrap_keys(client: &Connection, passphrase: &str) -> Result<(KeyId, HashMap<KeyId, Vec<u8>>)> {
+    let (salt, current): (Vec<u8>, Vec<u8>) = client.query_row(
+        "SELECT salt, key_id FROM encryption WHERE id = 1", (), |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let kek = derive(passphrase, &salt)?;
+    let mut raw = HashMap::new();
+    let mut stmt = client.prepare_cached("SELECT id, key FROM encryption_key")?;
+    let mut rows = stmt.query(named_params! {})?;
+    while let Some(row) = rows.next()? {
+        let id: Vec<u8> = row.get(0)?;
+        let key: Vec<u8> = row.get(1)?;
+        let key = decrypt(&kek, &key).map_err(|_| anyhow!("wrong passphrase"))?;
+        raw.insert(id.as_slice().try_into()?, key);
+    }
+    let current: KeyId = current.as_slice().try_into()?;
+    if !raw.contains_key(&current) {
+        bail!("current encryption key not found");
+    }
+    Ok((current, raw))
+}
+
+// 生成新的数据密钥 用新的口令重新保存全部数据密钥
+fn save_keys(client: &Connection, passphrase: &str, old: &HashMap<KeyId, Vec<u8>>) -> Result<Keys> {
+    let salt: [u8; SALT_LEN] = random();
+    let kek = derive(passphrase, &salt)?;
+    let current: KeyId = random();
+    let mut raw = old.clone();
+    raw.insert(current, Aes256Gcm::generate_key(&mut OsRng).to_vec());
+    let now = chrono::Local::now().timestamp_millis();
+    client.execute("DELETE FROM encryption_key", ())?;
+    for (id, key) in &raw {
+        client.execute("INSERT INTO encryption_key (id, key, ctime) VALUES (?1, ?2, ?3)",
+                       (&id[..], &encrypt(&kek, id, key)?, &now))?;
+    }
+    // 与密钥在同一个事务中标记需要重新加密 重新加密完成后才清除
+    client.execute("INSERT OR REPLACE INTO encryption (id, salt, key_id, ctime, reseal_pending) VALUES (1, ?1, ?2, ?3, 1)",
+                   (&salt[..], &current[..], &now))?;
+    Ok(to_keys(current, &raw))
+}
+
+fn check_passphrase(passphrase: &str) -> Result<()> {
+    if passphrase.is_empty() {
+        bail!("passphrase can not be empty");
+    }
+    Ok(())
+}
+
+// 重新加密一列中没有使用当前密钥加密的数据 返回重新加密的行数
+fn reseal_column(column: &str) -> Result<usize> {
+    let prefix = {
+        let state = STATE.read().unwrap();
+        let keys = state.keys.as_ref().ok_or(anyhow!("image history is encrypted and locked"))?;
+        [&MAGIC[..], &keys.current[..]].concat()
+    };
+    let sql = format!(r#"SELECT id, {0} FROM image WHERE id > :last_id AND {0} IS NOT NULL
+                      AND (typeof({0}) != 'blob' OR substr({0}, 1, :len) != :prefix) ORDER BY id LIMIT :limit"#, column);
+    let update = format!("UPDATE image SET {0} = ?3 WHERE id = ?1 AND {0} IS ?2", column);
+    let mut last_id: i64 = 0;
+    let mut count = 0;
+    loop {
+        let client = client()?;
+        let mut row_list: Vec<(i64, Value)> = vec![];
+        {
+            let mut stmt = client.prepare_cached(sql.as_str())?;
+            let mut rows = stmt.query(named_params! {
+                ":last_id": last_id,
+                ":len": prefix.len() as i64,
+                ":prefix": prefix,
+                ":limit": BATCH,
+            })?;
+            while let Some(row) = rows.next()? {
+                row_list.push((row.get(0)?, row.get(1)?));
+            }
+        }
+        if row_list.is_empty() {
+            break;
+        }
+        for (id, old) in &row_list {
+            last_id = *id;
+            let data = match old {
+                Value::Text(text) => seal(text.as_bytes())?,
+                Value::Blob(data) => seal(&open(data.clone())?)?,
+                _ => continue,
+            };
+            // 只在这期间没有被修改时才更新
+            count += client.execute(update.as_str(), (id, old, &data))?;
+        }
+    }
+    Ok(count)
+}
+
+// 把全部数据重新加密为当前密钥 完成后删除旧的密钥
+fn reseal_all() -> Result<()> {
+    loop {
+        let mut count = 0;
+        for column in COLUMNS {
+            count += reseal_column(column)?;
+        }
+        count += blob::reseal()?;
+        if count == 0 {
+            break;
+        }
+        info!("encryption resealed {} items", count);
+    }
+    let current = match &STATE.read().unwrap().keys {
+        Some(keys) => keys.current,
+        None => return Ok(()),
+    };
+    client()?.execute("DELETE FROM encryption_key WHERE id != ?1", (&current[..],))?;
+    if let Some(keys) = &mut STATE.write().unwrap().keys {
+        keys.all.retain(|id, _| id == &current);
+    }
+    Ok(())
+}
+
+// 重新加密全部数据并清理空闲页中的明文 全部成功后清除重新加密的标记
+fn finish_reseal() -> Result<()> {
+    reseal_all()?;
+    let client = client()?;
+    // 删除的明文还留在空闲页中
+    client.execute_batch("VACUUM")?;
+    client.execute("UPDATE encryption SET reseal_pending = 0 WHERE id = 1", ())?;
+    info!("encryption reseal finished");
+    Ok(())
+}
+
+fn is_reseal_pending(client: &Connection) -> Result<bool> {
+    let pending: Option<bool> = client.query_row("SELECT reseal_pending FROM encryption WHERE id = 1", (), |row| row.get(0)).optional()?;
+    Ok(pending.unwrap_or(false))
+}
+
+// 开启加密 加密已有的图片、缩略图、OCR文本、标题和备注
+// 中途失败或者退出时保留重新加密的标记 下次解锁后继续
+pub fn enable(passphrase: &str) -> Result<()> {
+    check_passphrase(passphrase)?;
+    if is_enabled() {
+        bail!("encryption is already enabled");
+    }
+    {
+        let mut client = client()?;
+        let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let keys = save_keys(&tx, passphrase, &HashMap::new())?;
+        // 加密后不再维护全文索引 否则索引中会留下明文
+        tx.execute("DELETE FROM image_fts", ())?;
+        tx.commit()?;
+        let mut state = STATE.write().unwrap();
+        state.enabled = true;
+        state.keys = Some(keys);
+    }
+    finish_reseal()
+}
+
+pub fn unlock(passphrase: &str) -> Result<()> {
+    if !is_enabled() {
+        bail!("encryption is not enabled");
+    }
+    let client = client()?;
+    let (current, raw) = unwrap_keys(&client, passphrase)?;
+    let resume = is_reseal_pending(&client)?;
+    STATE.write().unwrap().keys = Some(to_keys(current, &raw));
+    // 上次开启加密或修改口令时没有完成重新加密
+    if resume {
+        std::thread::spawn(|| {
+            if let Err(err) = finish_reseal() {
+                log::error!("encryption reseal with error: {}", err.to_string());
+            }
+        });
+    }
+    Ok(())
+}
+
+// 修改口令 生成新的数据密钥并重新加密全部数据
+pub fn change_passphrase(old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+    check_passphrase(new_passphrase)?;
+    if !is_enabled() {
+        bail!("encryption is not enabled");
+    }
+    {
+        let mut client = client()?;
+        let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let (_, raw) = unwrap_keys(&tx, old_passphrase)?;
+        let keys = save_keys(&tx, new_passphrase, &raw)?;
+        tx.commit()?;
+        STATE.write().unwrap().keys = Some(keys);
+    }
+    finish_reseal()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use crate::client::sqlite::testing;
+    use super::*;
+
+    const SUM: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+    const IMAGE: &[u8] = b"image data";
+    const THUMBNAIL: &[u8] = b"thumbnail data";
+    const OCR: &str = r#"{"code":100,"data":[{"text":"hello world"}]}"#;
+
+    // 一张没有加密的图片
+    fn seed() {
+        blob::put(SUM, IMAGE).unwrap();
+        client().unwrap().execute(r#"INSERT INTO image (id, size, width, height, ctime, mtime, sum, thumbnail, ocr, title)
+            VALUES (1, 1, 1, 1, 1, 1, ?1, ?2, ?3, 'title')"#, (SUM, THUMBNAIL, OCR)).unwrap();
+    }
+
+    // 存储中的原始数据使用的密钥
+    fn raw_key_ids() -> Vec<Option<KeyId>> {
+        let client = client().unwrap();
+        let (thumbnail, ocr, title): (Vec<u8>, Value, Value) = client.query_row(
+            "SELECT thumbnail, ocr, title FROM image WHERE id = 1", (), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        let image = fs::read(blob::get_path(SUM).unwrap()).unwrap();
+        // 没有加密的文本以TEXT保存
+        let [ocr, title] = [ocr, title].map(|value| match value {
+            Value::Blob(data) => data,
+            _ => vec![],
+        });
+        [image, thumbnail, ocr, title].iter().map(|data| get_key_id(data)).collect()
+    }
+
+    fn check_data() {
+        let client = client().unwrap();
+        assert_eq!(blob::get(SUM).unwrap(), IMAGE);
+        let (thumbnail, ocr, title): (Value, Value, Value) = client.query_row(
+            "SELECT thumbnail, ocr, title FROM image WHERE id = 1", (), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        assert_eq!(open_binary(thumbnail).unwrap().as_deref(), Some(THUMBNAIL));
+        assert_eq!(open_text(ocr).unwrap().as_deref(), Some(OCR));
+        assert_eq!(open_text(title).unwrap().as_deref(), Some("title"));
+    }
+
+    fn lock() {
+        load(&client().unwrap()).unwrap();
+        assert!(is_locked());
+    }
+
+    fn current_key() -> KeyId {
+        STATE.read().unwrap().keys.as_ref().unwrap().current
+    }
+
+    #[test]
+    fn enable_lock_and_unlock() {
+        let _lock = testing::reset();
+        seed();
+        enable("secret").unwrap();
+        assert!(is_enabled() && !is_locked());
+        let current = current_key();
+        assert!(raw_key_ids().iter().all(|id| id == &Some(current)));
+        assert!(!is_reseal_pending(&client().unwrap()).unwrap());
+        check_data();
+        assert!(enable("secret").is_err());
+
+        lock();
+        assert!(blob::get(SUM).is_err());
+        assert!(seal(IMAGE).is_err());
+        assert!(check_unlocked().is_err());
+        // 口令错误
+        let err = unlock("wrong").unwrap_err();
+        assert!(err.to_string().contains("wrong passphrase"), "{}", err);
+        assert!(is_locked());
+        unlock("secret").unwrap();
+        check_data();
+    }
+
+    #[test]
+    fn change_passphrase_reseals() {
+        let _lock = testing::reset();
+        seed();
+        enable("old").unwrap();
+        let old = current_key();
+        assert!(change_passphrase("wrong", "new").is_err());
+        assert!(change_passphrase("old", "").is_err());
+        change_passphrase("old", "new").unwrap();
+        let new = current_key();
+        assert_ne!(old, new);
+        assert!(raw_key_ids().iter().all(|id| id == &Some(new)));
+        // 旧的数据密钥已经删除
+        let count: i64 = client().unwrap().query_row("SELECT COUNT(*) FROM encryption_key", (), |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+        check_data();
+
+        lock();
+        assert!(unlock("old").is_err());
+        unlock("new").unwrap();
+        check_data();
+    }
+
+    // 开启加密时只保存了密钥就退出 解锁后继续重新加密
+    #[test]
+    fn resume_interrupted_reseal() {
+        let _lock = testing::reset();
+        seed();
+        {
+            let mut client = client().unwrap();
+            let tx = client.transaction_with_behavior(TransactionBehavior::Immediate).unwrap();
+            save_keys(&tx, "secret", &HashMap::new()).unwrap();
+            tx.commit().unwrap();
+        }
+        lock();
+        assert!(is_reseal_pending(&client().unwrap()).unwrap());
+        assert!(raw_key_ids().iter().all(|id| id.is_none()));
+        unlock("secret").unwrap();
+        let current = current_key();
+        let start = Instant::now();
+        while is_reseal_pending(&client().unwrap()).unwrap() {
+            assert!(start.elapsed() < Duration::from_secs(30), "reseal is not resumed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(raw_key_ids().iter().all(|id| id == &Some(current)));
+        check_data();
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use anyhow::Result;
+use log::warn;
+use once_cell::sync::Lazy;
+use rusqlite::{Connection, named_params};
+use crate::client::crypto;
+use crate::model::OCR;
+
+// 少于3个字符的词无法使用trigram索引 只能逐行匹配
+const MIN_MATCH_CHARS: usize = 3;
+
+// 与image_fts结构相同的空表 只用于检查查询表达式的语法
+static VALIDATOR: Lazy<Mutex<Connection>> = Lazy::new(|| {
+    let connection = Connection::open_in_memory().unwrap();
+    connection.execute_batch("CREATE VIRTUAL TABLE validator USING fts5 (text, title, note, tokenize = 'trigram')").unwrap();
+    Mutex::new(connection)
+});
+
+// 最多保留的相关度快照数量 超出时丢弃最久没有使用的
+const MAX_SNAPSHOTS: usize = 16;
+
+// 一次搜索中每张图片的BM25相关度
+struct RankSnapshot {
+    ranks: HashMap<i64, f64>,
+    atime: Instant,
+}
+
+static SNAPSHOTS: Lazy<Mutex<HashMap<u64, RankSnapshot>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+static NEXT_SNAPSHOT: AtomicU64 = AtomicU64::new(1);
+
+// 单个搜索词对应的查询方式
+pub enum TextQuery {
+    // FTS5查询表达式 可以使用BM25排序
+    Match(String),
+    // 过短的词 使用LIKE匹配
+    Like(String),
+}
+
+// 按图片当前的OCR文本、标题和备注重建全文索引 全部为空时只删除
+// 开启加密后不再维护全文索引 避免留下明文
+pub fn update(client: &Connection, id: i64) -> Result<()> {
+    if crypto::is_enabled() {
+        client.execute("DELETE FROM image_fts WHERE rowid = ?1", (&id,))?;
+        return Ok(());
+    }
+    let (ocr, title, note): (Option<String>, Option<String>, Option<String>) = client.query_row(
+        "SELECT ocr, title, note FROM image WHERE id = :id",
+        named_params! { ":id": id },
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let text = match ocr {
+        Some(ocr) => serde_json::from_str::<OCR>(ocr.as_str())?.text(),
+        None => "".to_string(),
+    };
+    client.execute("DELETE FROM image_fts WHERE rowid = ?1", (&id,))?;
+    if !text.is_empty() || title.is_some() || note.is_some() {
+        client.execute("INSERT INTO image_fts (rowid, text, title, note) VALUES (?1, ?2, ?3, ?4)", (&id, &text, &title, &note))?;
+    }
+    Ok(())
+}
+
+// FTS5能否解析查询表达式
+fn is_valid(query: &str) -> bool {
+    let validator = VALIDATOR.lock().unwrap_or_else(|err| err.into_inner());
+    validator.query_row("SELECT COUNT(*) FROM validator WHERE validator MATCH ?1", (query,), |_| Ok(())).is_ok()
+}
+
+// 整个搜索词能被FTS5解析时原样使用 支持短语、括号和AND/OR/NOT
+// 否则每个词分别作为一个短语 词之间为与的关系
+// trigram分词只做子串匹配 过短的词不能匹配任何行 这时使用LIKE匹配整个搜索词
+pub fn parse_query(text: &str) -> TextQuery {
+    let text = text.trim();
+    // 检查长度时跳过AND/OR/NOT 只有这些词时作为普通的词
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut terms: Vec<&str> = words.iter().cloned().filter(|word| !matches!(*word, "AND" | "OR" | "NOT")).collect();
+    if terms.is_empty() {
+        terms = words.clone();
+    }
+    let short = words.is_empty() || terms.iter().any(|word| {
+        word.trim_matches(|c| matches!(c, '"' | '(' | ')' | '*')).chars().count() < MIN_MATCH_CHARS
+    });
+    if short {
+        return TextQuery::Like(text.to_string());
+    }
+    if is_valid(text) {
+        return TextQuery::Match(text.to_string());
+    }
+    let query = text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ");
+    if is_valid(query.as_str()) {
+        return TextQuery::Match(query);
+    }
+    TextQuery::Like(text.to_string())
+}
+
+// BM25依赖整个索引的统计信息 其他图片插入或修改后同一张图片的值也会变化 不能直接作为翻页的排序键
+// 第一页查询时保存全部匹配图片的相关度 之后的页面使用同一个快照 返回快照ID
+// 快照已经被丢弃时使用同一个ID重新计算 排序可能与之前的页面不一致
+pub fn snapshot_rank(client: &Connection, query: &str, snapshot: Option<u64>) -> Result<u64> {
+    let mut snapshots = SNAPSHOTS.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(id) = snapshot {
+        if let Some(snapshot) = snapshots.get_mut(&id) {
+            snapshot.atime = Instant::now();
+            return Ok(id);
+        }
+        warn!("rank snapshot {} is evicted, rank again", id);
+    }
+    let mut stmt = client.prepare("SELECT rowid, bm25(image_fts) FROM image_fts WHERE image_fts MATCH ?1")?;
+    let ranks = stmt.query_map((query,), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<HashMap<i64, f64>>>()?;
+    let id = snapshot.unwrap_or_else(|| NEXT_SNAPSHOT.fetch_add(1, Ordering::Relaxed));
+    while snapshots.len() >= MAX_SNAPSHOTS {
+        let oldest = snapshots.iter().min_by_key(|(_, snapshot)| snapshot.atime).map(|(id, _)| *id);
+        match oldest {
+            Some(oldest) => snapshots.remove(&oldest),
+            None => break,
+        };
+    }
+    snapshots.insert(id, RankSnapshot { ranks, atime: Instant::now() });
+    Ok(id)
+}
+
+// 快照中图片的相关度 快照之后才匹配的图片没有相关度
+pub fn get_rank(snapshot: u64, id: i64) -> Option<f64> {
+    let snapshots = SNAPSHOTS.lock().unwrap_or_else(|err| err.into_inner());
+    snapshots.get(&snapshot).and_then(|snapshot| snapshot.ranks.get(&id).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(query: &TextQuery) -> Vec<i64> {
+        let client = Connection::open_in_memory().unwrap();
+        client.execute_batch(r#"
+        CREATE VIRTUAL TABLE image_fts USING fts5 (text, title, note, tokenize = 'trigram');
+        INSERT INTO image_fts (rowid, text) VALUES (1, 'f(x) = 1'), (2, 'shell'), (3, 'cats and dogs'), (4, 'hello world'), (5, 'NOT FOUND');
+        "#).unwrap();
+        let (sql, param) = match query {
+            TextQuery::Match(text) => ("SELECT rowid FROM image_fts WHERE image_fts MATCH ?1 ORDER BY rowid", text.clone()),
+            TextQuery::Like(text) => ("SELECT rowid FROM image_fts WHERE text LIKE ?1 ORDER BY rowid", format!("%{}%", text)),
+        };
+        let mut stmt = client.prepare(sql).unwrap();
+        let ids = stmt.query_map((param,), |row| row.get(0)).unwrap().collect::<rusqlite::Result<Vec<i64>>>().unwrap();
+        ids
+    }
+
+    #[test]
+    fn parse_valid_query() {
+        for (text, ids) in [("hello world", vec![4]), ("\"hello world\"", vec![4]), ("hello OR cats", vec![3, 4]), ("(cats)", vec![3])] {
+            let query = parse_query(text);
+            assert!(matches!(&query, TextQuery::Match(q) if q == text), "{}", text);
+            assert_eq!(search(&query), ids, "{}", text);
+        }
+    }
+
+    // 不能解析的搜索词中每个词作为一个短语 不会出现语法错误
+    #[test]
+    fn parse_invalid_query() {
+        for (text, expected, ids) in [("f(x)", "\"f(x)\"", vec![1]), ("NOT", "\"NOT\"", vec![5]), ("cats AND", "\"cats\" \"AND\"", vec![3]),
+            ("say \"hello", "\"say\" \"\"\"hello\"", vec![])] {
+            let query = parse_query(text);
+            assert!(matches!(&query, TextQuery::Match(q) if q == expected), "{}", text);
+            assert_eq!(search(&query), ids, "{}", text);
+        }
+    }
+
+    // 过短的词使用LIKE匹配整个搜索词
+    #[test]
+    fn parse_short_query() {
+        for (text, ids) in [("ab", vec![]), ("f(", vec![1]), ("hello wo", vec![4]), ("hello OR ab", vec![]), ("", vec![1, 2, 3, 4, 5])] {
+            let query = parse_query(text);
+            assert!(matches!(&query, TextQuery::Like(q) if q == text), "{}", text);
+            assert_eq!(search(&query), ids, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use anyhow::{bail, Result};
+use log::info;
+use rusqlite::{Connection, named_params, Transaction, TransactionBehavior};
+use crate::client::blob;
+use crate::common::pixel_sum;
+
+// 单个迁移 在事务中执行
+type Migration = fn(&Transaction) -> Result<()>;
+
+// 按顺序排列的迁移 执行完第i个迁移后数据库版本号为i+1 只能在末尾追加 不能修改已经发布的迁移
+const MIGRATIONS: &[Migration] = &[
+    v1_create_image,
+    v2_move_image_to_blob,
+    v3_image_occurrence,
+    v4_image_phash,
+    v5_image_thumbnail,
+    v6_image_fts,
+    v7_tag,
+    v8_image_pinned,
+    v9_collection,
+    v10_image_title_note,
+    v11_image_deleted_at,
+    v12_encryption,
+    v13_image_usage,
+    v14_image_dimension_index,
+    v15_image_palette,
+    v16_encryption_reseal,
+];
+
+// 初始表结构 老版本程序创建的数据库版本号为0 但已经存在这些表
+fn v1_create_image(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    CREATE TABLE IF NOT EXISTS image (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        image BLOB,
+        ocr TEXT,
+        size INTEGER,
+        width INTEGER,
+        height INTEGER,
+        ctime INTEGER,
+        mtime INTEGER,
+        sum VARCHAR(64)
+    );
+    CREATE INDEX IF NOT EXISTS index_mtime ON image (mtime);
+    CREATE INDEX IF NOT EXISTS index_sum ON image (sum);
+    "#)?;
+    Ok(())
+}
+
+// 把图片从image表移到按内容寻址的存储中 数据库中只保留元数据
+fn v2_move_image_to_blob(tx: &Transaction) -> Result<()> {
+    let mut id_list: Vec<i64> = vec![];
+    {
+        let mut stmt = tx.prepare("SELECT id FROM image WHERE image IS NOT NULL")?;
+        let mut rows = stmt.query(named_params! {})?;
+        while let Some(row) = rows.next()? {
+            id_list.push(row.get(0)?);
+        }
+    }
+    // 逐行读取 避免把所有图片一次性读进内存
+    for id in &id_list {
+        let (image, sum): (Vec<u8>, Option<String>) = tx.query_row(
+            "SELECT image, sum FROM image WHERE id = :id",
+            named_params! { ":id": id },
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let sum = match sum {
+            Some(sum) if !sum.is_empty() => sum,
+            _ => {
+                let sum = sha256::digest(image.as_slice());
+                tx.execute("UPDATE image SET sum = ?2 WHERE id = ?1", (id, &sum))?;
+                sum
+            }
+        };
+        blob::put(&sum, image.as_slice())?;
+    }
+    if !id_list.is_empty() {
+        info!("moved {} images to blob store", id_list.len());
+    }
+    tx.execute_batch("ALTER TABLE image DROP COLUMN image")?;
+    Ok(())
+}
+
+// 记录图片每次出现的时间和来源 并把sum改为根据解码后的像素计算 合并重复的图片
+fn v3_image_occurrence(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    CREATE TABLE image_occurrence (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        image_id INTEGER NOT NULL REFERENCES image (id) ON DELETE CASCADE,
+        ctime INTEGER NOT NULL,
+        source TEXT
+    );
+    CREATE INDEX index_occurrence_image_id ON image_occurrence (image_id, ctime);
+    INSERT INTO image_occurrence (image_id, ctime, source) SELECT id, ctime, NULL FROM image;
+    "#)?;
+    let mut row_list: Vec<(i64, String)> = vec![];
+    {
+        let mut stmt = tx.prepare("SELECT id, sum FROM image")?;
+        let mut rows = stmt.query(named_params! {})?;
+        while let Some(row) = rows.next()? {
+            row_list.push((row.get(0)?, row.get(1)?));
+        }
+    }
+    for (id, sum) in &row_list {
+        let data = blob::get(sum)?;
+        let image = image::load_from_memory(data.as_slice())?.into_rgba8();
+        let new_sum = pixel_sum(image.width(), image.height(), image.as_raw());
+        if &new_sum == sum {
+            continue;
+        }
+        // 旧的文件在启动时的清理中删除
+        blob::put(&new_sum, data.as_slice())?;
+        tx.execute("UPDATE image SET sum = ?2 WHERE id = ?1", (id, &new_sum))?;
+    }
+    // 相同sum的记录只保留最近的一条 出现记录合并到保留的记录上
+    tx.execute_batch(r#"
+    UPDATE image_occurrence SET image_id = (
+        SELECT keep.id FROM image AS keep, image AS cur
+        WHERE cur.id = image_occurrence.image_id AND keep.sum = cur.sum
+        ORDER BY keep.mtime DESC, keep.id DESC LIMIT 1
+    );
+    DELETE FROM image WHERE id NOT IN (SELECT image_id FROM image_occurrence);
+    UPDATE image SET ctime = (SELECT MIN(o.ctime) FROM image_occurrence AS o WHERE o.image_id = image.id);
+    "#)?;
+    Ok(())
+}
+
+// 感知哈希 已有的图片由regular::backfill补充计算
+fn v4_image_phash(tx: &Transaction) -> Result<()> {
+    tx.execute_batch("ALTER TABLE image ADD COLUMN phash INTEGER")?;
+    Ok(())
+}
+
+// 缩略图 体积很小 直接保存在数据库中 已有的图片由regular::backfill补充生成
+fn v5_image_thumbnail(tx: &Transaction) -> Result<()> {
+    tx.execute_batch("ALTER TABLE image ADD COLUMN thumbnail BLOB")?;
+    Ok(())
+}
+
+// OCR文本的全文索引 rowid与image表的id一致 由regular::ocr维护
+fn v6_image_fts(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    CREATE VIRTUAL TABLE image_fts USING fts5 (text, tokenize = 'trigram');
+    INSERT INTO image_fts (rowid, text)
+        SELECT image.id, GROUP_CONCAT(JSON_EXTRACT(j.value, '$.text'), CHAR(10))
+        FROM image, JSON_EACH(JSON_EXTRACT(image.ocr, '$.data')) AS j
+        WHERE JSON_EXTRACT(image.ocr, '$.code') = 100
+        GROUP BY image.id;
+    CREATE TRIGGER image_fts_delete AFTER DELETE ON image BEGIN
+        DELETE FROM image_fts WHERE rowid = old.id;
+    END;
+    "#)?;
+    Ok(())
+}
+
+// 标签以及图片与标签的多对多关联
+fn v7_tag(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    CREATE TABLE tag (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE,
+        ctime INTEGER NOT NULL
+    );
+    CREATE TABLE image_tag (
+        image_id INTEGER NOT NULL REFERENCES image (id) ON DELETE CASCADE,
+        tag_id INTEGER NOT NULL REFERENCES tag (id) ON DELETE CASCADE,
+        PRIMARY KEY (image_id, tag_id)
+    );
+    CREATE INDEX index_image_tag_tag_id ON image_tag (tag_id);
+    "#)?;
+    Ok(())
+}
+
+// 置顶收藏的图片 不会被自动清理
+fn v8_image_pinned(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    ALTER TABLE image ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX index_pinned_mtime ON image (pinned, mtime);
+    "#)?;
+    Ok(())
+}
+
+// 图集 图集和图集中的图片都按position排序
+fn v9_collection(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    CREATE TABLE collection (
+        id       INTEGER PRIMARY KEY AUTOINCREMENT,
+        name     TEXT    NOT NULL,
+        position INTEGER NOT NULL,
+        ctime    INTEGER NOT NULL
+    );
+    CREATE TABLE collection_image (
+        collection_id INTEGER NOT NULL REFERENCES collection (id) ON DELETE CASCADE,
+        image_id      INTEGER NOT NULL REFERENCES image (id) ON DELETE CASCADE,
+        position      INTEGER NOT NULL,
+        PRIMARY KEY (collection_id, image_id)
+    );
+    CREATE INDEX index_collection_image_position ON collection_image (collection_id, position);
+    CREATE INDEX index_collection_image_image_id ON collection_image (image_id);
+    "#)?;
+    Ok(())
+}
+
+// 图片的标题和备注 全文索引无法增加列 重建后加入标题和备注
+fn v10_image_title_note(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    ALTER TABLE image ADD COLUMN title TEXT;
+    ALTER TABLE image ADD COLUMN note TEXT;
+    UPDATE image SET title = NULLIF(SUBSTR(TRIM(JSON_EXTRACT(ocr, '$.data[0].text')), 1, 64), '')
+        WHERE JSON_EXTRACT(ocr, '$.code') = 100;
+    DROP TRIGGER image_fts_delete;
+    CREATE VIRTUAL TABLE image_fts_v10 USING fts5 (text, title, note, tokenize = 'trigram');
+    INSERT INTO image_fts_v10 (rowid, text, title)
+        SELECT image_fts.rowid, image_fts.text, image.title
+        FROM image_fts JOIN image ON image.id = image_fts.rowid;
+    DROP TABLE image_fts;
+    ALTER TABLE image_fts_v10 RENAME TO image_fts;
+    CREATE TRIGGER image_fts_delete AFTER DELETE ON image BEGIN
+        DELETE FROM image_fts WHERE rowid = old.id;
+    END;
+    "#)?;
+    Ok(())
+}
+
+// 回收站 记录移入回收站的时间
+fn v11_image_deleted_at(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    ALTER TABLE image ADD COLUMN deleted_at INTEGER;
+    CREATE INDEX index_deleted_at ON image (deleted_at);
+    "#)?;
+    Ok(())
+}
+
+// 加密配置 数据密钥使用由口令派生的密钥加密后保存
+fn v12_encryption(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    CREATE TABLE encryption (
+        id     INTEGER PRIMARY KEY CHECK (id = 1),
+        salt   BLOB    NOT NULL,
+        key_id BLOB    NOT NULL,
+        ctime  INTEGER NOT NULL
+    );
+    CREATE TABLE encryption_key (
+        id    BLOB    PRIMARY KEY,
+        key   BLOB    NOT NULL,
+        ctime INTEGER NOT NULL
+    );
+    "#)?;
+    Ok(())
+}
+
+// 再次复制的次数和最近一次使用的时间 以及排序用的索引
+fn v13_image_usage(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    ALTER TABLE image ADD COLUMN use_count INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE image ADD COLUMN last_used_at INTEGER;
+    CREATE INDEX index_ctime ON image (ctime);
+    CREATE INDEX index_size ON image (size);
+    CREATE INDEX index_use_count ON image (use_count);
+    CREATE INDEX index_last_used_at ON image (last_used_at);
+    "#)?;
+    Ok(())
+}
+
+// 按尺寸和宽高比过滤用的索引 宽高比使用表达式索引 查询中的表达式需要完全一致
+fn v14_image_dimension_index(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    CREATE INDEX index_width_height ON image (width, height);
+    CREATE INDEX index_height ON image (height);
+    CREATE INDEX index_aspect_ratio ON image (CAST(width AS REAL) / height);
+    "#)?;
+    Ok(())
+}
+
+// 量化颜色直方图 按颜色搜索时不需要解码图片 历史图片由后台任务补充计算
+fn v15_image_palette(tx: &Transaction) -> Result<()> {
+    tx.execute_batch("ALTER TABLE image ADD COLUMN palette BLOB")?;
+    Ok(())
+}
+
+// 开启加密或修改口令后还没有完成重新加密的标记 完成前解锁时继续重新加密
+// 已经开启加密的数据库不确定上次是否完成 解锁时再检查一遍
+fn v16_encryption_reseal(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(r#"
+    ALTER TABLE encryption ADD COLUMN reseal_pending INTEGER NOT NULL DEFAULT 0;
+    UPDATE encryption SET reseal_pending = 1;
+    "#)?;
+    Ok(())
+}
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+pub fn get_version(client: &Connection) -> Result<i64> {
+    Ok(client.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+// 将数据库升级到最新版本
+pub fn migrate(client: &mut Connection) -> Result<()> {
+    let version = get_version(client)?;
+    let latest = latest_version();
+    if version < 0 || version > latest {
+        bail!("database version {} is not supported, latest supported version is {}", version, latest);
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let target = index as i64 + 1;
+        let tx = client.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        migration(&tx)?;
+        // user_version保存在数据库头部 跟随事务一起提交
+        tx.pragma_update(None, "user_version", target)?;
+        tx.commit()?;
+        info!("database migrated from version {} to {}", target - 1, target);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use image::{ImageEncoder, Rgba, RgbaImage};
+    use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+    use crate::client::sqlite::testing;
+    use crate::common::get_root;
+    use super::*;
+
+    const OCR: &str = r#"{"code":100,"data":[{"text":"hello world"}]}"#;
+
+    fn png(color: [u8; 4], compression: CompressionType) -> Vec<u8> {
+        let image = RgbaImage::from_pixel(4, 4, Rgba(color));
+        let mut data = Cursor::new(vec![]);
+        PngEncoder::new_with_quality(&mut data, compression, FilterType::NoFilter)
+            .write_image(image.as_raw(), 4, 4, image::ColorType::Rgba8).unwrap();
+        data.into_inner()
+    }
+
+    fn sum_of(data: &[u8]) -> String {
+        let image = image::load_from_memory(data).unwrap().into_rgba8();
+        pixel_sum(image.width(), image.height(), image.as_raw())
+    }
+
+    // 按版本号逐个执行迁移 得到指定版本的数据库 版本0为老版本程序创建的数据库
+    fn fixture(version: i64) -> Connection {
+        let path = get_root().join(format!("fixture-v{}.sqlite3", version));
+        let _ = fs::remove_file(path.as_path());
+        let mut client = Connection::open(path.as_path()).unwrap();
+        let tx = client.transaction().unwrap();
+        v1_create_image(&tx).unwrap();
+        for migration in MIGRATIONS.iter().take(version as usize).skip(1) {
+            migration(&tx).unwrap();
+        }
+        tx.pragma_update(None, "user_version", version).unwrap();
+        tx.commit().unwrap();
+        seed(&client, version);
+        client
+    }
+
+    // 两张相同像素不同编码的红色图片 以及一张带OCR文本的蓝色图片
+    fn seed(client: &Connection, version: i64) {
+        let red_fast = png([255, 0, 0, 255], CompressionType::Fast);
+        let red_best = png([255, 0, 0, 255], CompressionType::Best);
+        let blue = png([0, 0, 255, 255], CompressionType::Default);
+        assert_ne!(red_fast, red_best);
+        if version < 3 {
+            // 版本2之前图片保存在image列中 sum为文件的sha256 可能为空
+            let rows = [
+                (&red_fast, sha256::digest(red_fast.as_slice()), None, 1),
+                (&red_best, "".to_string(), None, 2),
+                (&blue, sha256::digest(blue.as_slice()), Some(OCR), 3),
+            ];
+            for (data, sum, ocr, ctime) in rows {
+                if version < 2 {
+                    client.execute("INSERT INTO image (image, ocr, size, width, height, ctime, mtime, sum) VALUES (?1, ?2, ?3, 4, 4, ?4, ?4, ?5)",
+                                   (data, ocr, data.len() as i64, ctime, &sum)).unwrap();
+                } else {
+                    let sum = sha256::digest(data.as_slice());
+                    blob::put(&sum, data).unwrap();
+                    client.execute("INSERT INTO image (ocr, size, width, height, ctime, mtime, sum) VALUES (?1, ?2, 4, 4, ?3, ?3, ?4)",
+                                   (ocr, data.len() as i64, ctime, &sum)).unwrap();
+                }
+            }
+            return;
+        }
+        // 版本3之后sum为像素的sum 重复的图片已经合并 出现记录单独保存
+        for (data, ocr, ctime, mtime) in [(&red_best, None, 1, 2), (&blue, Some(OCR), 3, 3)] {
+            let sum = sum_of(data);
+            blob::put(&sum, data).unwrap();
+            client.execute("INSERT INTO image (ocr, size, width, height, ctime, mtime, sum) VALUES (?1, ?2, 4, 4, ?3, ?4, ?5)",
+                           (ocr, data.len() as i64, ctime, mtime, &sum)).unwrap();
+            let id = client.last_insert_rowid();
+            for ctime in ctime..=mtime {
+                client.execute("INSERT INTO image_occurrence (image_id, ctime, source) VALUES (?1, ?2, NULL)", (id, ctime)).unwrap();
+            }
+            if ocr.is_none() {
+                continue;
+            }
+            // 版本6之后OCR文本在全文索引中 版本10之后可以手动设置标题
+            if version >= 10 {
+                client.execute("UPDATE image SET title = 'custom title' WHERE id = ?1", (id,)).unwrap();
+                client.execute("INSERT INTO image_fts (rowid, text, title) VALUES (?1, 'hello world', 'custom title')", (id,)).unwrap();
+            } else if version >= 6 {
+                client.execute("INSERT INTO image_fts (rowid, text) VALUES (?1, 'hello world')", (id,)).unwrap();
+            }
+        }
+    }
+
+    fn names(client: &Connection, sql: &str) -> Vec<String> {
+        let mut stmt = client.prepare(sql).unwrap();
+        let names = stmt.query_map((), |row| row.get(0)).unwrap().collect::<rusqlite::Result<Vec<String>>>().unwrap();
+        names
+    }
+
+    fn check(client: &Connection, version: i64) {
+        assert_eq!(get_version(client).unwrap(), latest_version());
+        let schema = names(client, "SELECT name FROM sqlite_master");
+        for name in ["image", "image_occurrence", "image_fts", "tag", "image_tag", "collection", "collection_image",
+            "encryption", "encryption_key", "image_fts_delete", "index_aspect_ratio", "index_width_height", "index_use_count"] {
+            assert!(schema.contains(&name.to_string()), "v{}: missing {}", version, name);
+        }
+        let columns = names(client, "SELECT name FROM pragma_table_info('image')");
+        for column in ["phash", "thumbnail", "pinned", "title", "note", "deleted_at", "use_count", "last_used_at", "palette"] {
+            assert!(columns.contains(&column.to_string()), "v{}: missing column {}", version, column);
+        }
+        assert!(!columns.contains(&"image".to_string()), "v{}: image column is not dropped", version);
+        let columns = names(client, "SELECT name FROM pragma_table_info('encryption')");
+        assert!(columns.contains(&"reseal_pending".to_string()), "v{}: missing column reseal_pending", version);
+
+        // 图片文件都在存储中 sum与像素一致 相同像素的图片合并为一条
+        let rows: Vec<(i64, String, i64, i64)> = {
+            let mut stmt = client.prepare("SELECT id, sum, ctime, (SELECT COUNT(*) FROM image_occurrence AS o WHERE o.image_id = image.id) FROM image ORDER BY ctime").unwrap();
+            let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+                .collect::<rusqlite::Result<Vec<_>>>().unwrap();
+            rows
+        };
+        assert_eq!(rows.len(), 2, "v{}: {:?}", version, rows);
+        for (_, sum, _, _) in &rows {
+            assert_eq!(&sum_of(blob::get(sum).unwrap().as_slice()), sum, "v{}", version);
+        }
+        let (red, blue) = (&rows[0], &rows[1]);
+        assert_eq!((red.2, red.3), (1, 2), "v{}: red occurrences", version);
+        assert_eq!((blue.2, blue.3), (3, 1), "v{}: blue occurrences", version);
+
+        // OCR文本可以通过全文索引搜索 标题从OCR生成或者保留手动设置的标题
+        let matched: Vec<i64> = {
+            let mut stmt = client.prepare("SELECT rowid FROM image_fts WHERE image_fts MATCH '\"hello\"'").unwrap();
+            let matched = stmt.query_map((), |row| row.get(0)).unwrap().collect::<rusqlite::Result<Vec<i64>>>().unwrap();
+            matched
+        };
+        assert_eq!(matched, vec![blue.0], "v{}: fts", version);
+        let expected = if version >= 10 { "custom title" } else { "hello world" };
+        let (title, fts_title): (Option<String>, Option<String>) = client.query_row(
+            "SELECT image.title, image_fts.title FROM image JOIN image_fts ON image_fts.rowid = image.id WHERE image.id = ?1",
+            (blue.0,), |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!(title.as_deref(), Some(expected), "v{}: title", version);
+        assert_eq!(fts_title.as_deref(), Some(expected), "v{}: fts title", version);
+    }
+
+    #[test]
+    fn migrate_from_every_version() {
+        let _lock = testing::lock();
+        for version in 0..latest_version() {
+            let mut client = fixture(version);
+            migrate(&mut client).unwrap();
+            check(&client, version);
+            // 已经是最新版本时不做任何修改
+            migrate(&mut client).unwrap();
+            check(&client, version);
+        }
+    }
+
+    #[test]
+    fn refuse_newer_version() {
+        let _lock = testing::lock();
+        let mut client = fixture(latest_version());
+        client.pragma_update(None, "user_version", latest_version() + 1).unwrap();
+        let err = migrate(&mut client).unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{}", err);
+        assert_eq!(get_version(&client).unwrap(), latest_version() + 1);
+    }
+}
@@ -0,0 +1,119 @@
+use rusqlite::{params_from_iter, ParamsFromIter};
+use rusqlite::types::Value;
+
+// 带绑定参数的SQL 参数按照占位符?出现的顺序保存
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    sql: String,
+    params: Vec<Value>,
+}
+
+impl Query {
+    pub fn new(sql: &str) -> Self {
+        Self {
+            sql: sql.to_string(),
+            params: vec![],
+        }
+    }
+
+    // 追加不带参数的SQL
+    pub fn push(&mut self, sql: &str) -> &mut Self {
+        self.sql.push(' ');
+        self.sql.push_str(sql);
+        self.sql.push(' ');
+        self
+    }
+
+    // 追加SQL以及其中每个?对应的参数
+    pub fn push_bind<I: IntoIterator<Item = V>, V: Into<Value>>(&mut self, sql: &str, params: I) -> &mut Self {
+        self.push(sql);
+        self.params.extend(params.into_iter().map(|v| v.into()));
+        debug_assert_eq!(self.sql.matches('?').count(), self.params.len());
+        self
+    }
+
+    // 追加形如(?, ?, ?)的列表 列表为空时追加(NULL) 不匹配任何值
+    pub fn push_list<I: IntoIterator<Item = V>, V: Into<Value>>(&mut self, params: I) -> &mut Self {
+        let params: Vec<Value> = params.into_iter().map(|v| v.into()).collect();
+        if params.is_empty() {
+            return self.push("(NULL)");
+        }
+        let placeholder = vec!["?"; params.len()].join(", ");
+        self.push(format!("({})", placeholder).as_str());
+        self.params.extend(params);
+        self
+    }
+
+    // 追加另一个Query
+    pub fn append(&mut self, other: &Query) -> &mut Self {
+        self.push(other.sql.as_str());
+        self.params.extend(other.params.iter().cloned());
+        self
+    }
+
+    pub fn sql(&self) -> &str {
+        self.sql.as_str()
+    }
+
+    pub fn params(&self) -> ParamsFromIter<&Vec<Value>> {
+        params_from_iter(&self.params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use super::*;
+
+    fn check(query: &Query) {
+        assert_eq!(query.sql().matches('?').count(), query.params.len(), "{}", query.sql());
+    }
+
+    #[test]
+    fn bind_in_order() {
+        let mut query = Query::default();
+        query.push_bind("SELECT ? AS a,", [1]);
+        let mut other = Query::default();
+        other.push_bind("? AS b, ? AS c", ["x".to_string(), "y".to_string()]);
+        query.append(&other).push_bind(", ? AS d", [2.5]);
+        check(&query);
+        assert_eq!(query.params, vec![Value::Integer(1), Value::Text("x".into()), Value::Text("y".into()), Value::Real(2.5)]);
+        let client = Connection::open_in_memory().unwrap();
+        let row: (i64, String, String, f64) = client.query_row(query.sql(), query.params(), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+        assert_eq!(row, (1, "x".to_string(), "y".to_string(), 2.5));
+    }
+
+    #[test]
+    fn push_list() {
+        let client = Connection::open_in_memory().unwrap();
+        client.execute_batch("CREATE TABLE t (id INTEGER); INSERT INTO t VALUES (1), (2), (3);").unwrap();
+        for (list, expected) in [(vec![], vec![]), (vec![2], vec![2]), (vec![3, 1, 3], vec![1, 3])] {
+            let mut query = Query::new("SELECT id FROM t WHERE id IN");
+            query.push_list(list.clone()).push("ORDER BY id");
+            check(&query);
+            let mut stmt = client.prepare(query.sql()).unwrap();
+            let ids = stmt.query_map(query.params(), |row| row.get(0)).unwrap().collect::<rusqlite::Result<Vec<i64>>>().unwrap();
+            assert_eq!(ids, expected, "{:?}", list);
+        }
+        let mut query = Query::default();
+        query.push_list(Vec::<i64>::new());
+        assert_eq!(query.sql().trim(), "(NULL)");
+        assert!(query.params.is_empty());
+    }
+
+    // 参数中的引号、通配符和分号原样绑定 不会改变SQL
+    #[test]
+    fn hostile_params() {
+        let client = Connection::open_in_memory().unwrap();
+        client.execute_batch("CREATE TABLE t (name TEXT); INSERT INTO t VALUES ('a');").unwrap();
+        for text in ["'; DROP TABLE t; --", "\"quoted\"", "100%", "?", "a' OR '1' = '1"] {
+            let mut query = Query::default();
+            query.push_bind("SELECT COUNT(*) FROM t WHERE name = ? OR name IN", [text.to_string()]).push_list([text.to_string()]);
+            check(&query);
+            let count: i64 = client.query_row(query.sql(), query.params(), |row| row.get(0)).unwrap();
+            assert_eq!(count, 0, "{}", text);
+        }
+        let count: i64 = client.query_row("SELECT COUNT(*) FROM t", (), |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+    }
+}
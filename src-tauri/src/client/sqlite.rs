@@ -1,6 +1,13 @@
-use std::ops::Deref;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::Result;
 use once_cell::sync::Lazy;
 use rusqlite::Connection;
+use rusqlite::functions::FunctionFlags;
+use color_space::{Lab, Rgb};
+use crate::analyzer::{palette, phash};
+use crate::client::fts;
 use crate::common::get_root;
 
 static PATH: Lazy<String> = Lazy::new(|| {
@@ -13,6 +20,150 @@ pub fn get_database_path() -> &'static String {
     PATH.deref()
 }
 
-pub fn client() -> Connection {
-    Connection::open(PATH.deref().as_str()).unwrap()
-}
\ No newline at end of file
+// 连接池中最多保留的空闲连接数
+const MAX_IDLE: usize = 8;
+
+// 等待其他连接释放锁的最长时间
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 每个连接缓存的预编译语句数量
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+static POOL: Lazy<Mutex<Vec<Connection>>> = Lazy::new(|| {
+    Mutex::new(vec![])
+});
+
+fn open() -> Result<Connection> {
+    let connection = Connection::open(PATH.deref().as_str())?;
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    // WAL模式下读写互不阻塞
+    // 写事务需要使用IMMEDIATE 先读后写的DEFERRED事务在其他连接提交后会直接返回SQLITE_BUSY_SNAPSHOT 不会等待busy_timeout
+    connection.execute_batch(r#"
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    PRAGMA foreign_keys = ON;
+    "#)?;
+    connection.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+    // 感知哈希之间的汉明距离 任意一个为NULL时返回NULL
+    connection.create_scalar_function(
+        "hamming_distance",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let a: Option<i64> = ctx.get(0)?;
+            let b: Option<i64> = ctx.get(1)?;
+            Ok(a.zip(b).map(|(a, b)| phash::distance(a, b) as i64))
+        },
+    )?;
+    // 颜色直方图中与指定颜色相近的像素占比 参数为直方图、R、G、B和可接受的DeltaE 直方图为NULL时返回NULL
+    connection.create_scalar_function(
+        "color_coverage",
+        5,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let data: Option<Vec<u8>> = ctx.get(0)?;
+            let color = Lab::from(Rgb::new(ctx.get::<f64>(1)?, ctx.get::<f64>(2)?, ctx.get::<f64>(3)?));
+            let difference: f64 = ctx.get(4)?;
+            Ok(data.map(|data| palette::coverage(data.as_slice(), &color, difference)))
+        },
+    )?;
+    // 相关度快照中图片的BM25相关度 参数为快照ID和图片ID 不在快照中时返回NULL
+    connection.create_scalar_function(
+        "fts_rank",
+        2,
+        FunctionFlags::SQLITE_UTF8,
+        |ctx| {
+            let snapshot: i64 = ctx.get(0)?;
+            let id: i64 = ctx.get(1)?;
+            Ok(fts::get_rank(snapshot as u64, id))
+        },
+    )?;
+    Ok(connection)
+}
+
+// 从连接池取出的连接 释放时自动放回连接池
+pub struct Client {
+    connection: Option<Connection>,
+}
+
+impl Deref for Client {
+    type Target = Connection;
+
+    fn deref(&self) -> &Self::Target {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Client {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => return,
+        };
+        // 仍处于事务中的连接不能复用
+        if !connection.is_autocommit() {
+            return;
+        }
+        if let Ok(mut pool) = POOL.lock() {
+            if pool.len() < MAX_IDLE {
+                pool.push(connection);
+            }
+        }
+    }
+}
+
+// 关闭全部空闲连接 恢复备份后使用新的连接
+pub fn reset_pool() {
+    if let Ok(mut pool) = POOL.lock() {
+        pool.clear();
+    }
+}
+
+pub fn client() -> Result<Client> {
+    let connection = POOL.lock().ok().and_then(|mut pool| pool.pop());
+    let connection = match connection {
+        Some(connection) => connection,
+        None => open()?,
+    };
+    Ok(Client { connection: Some(connection) })
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::fs;
+    use std::sync::{Mutex, MutexGuard};
+    use crate::client::{crypto, migration};
+    use crate::common::get_root;
+    use crate::initialize::AUTO_VACUUM_INCREMENTAL;
+    use super::{client, get_database_path, reset_pool};
+
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    // 测试共用同一个数据库和图片存储 需要依次执行
+    // 同时关闭其他测试开启的加密
+    pub fn lock() -> MutexGuard<'static, ()> {
+        let guard = LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        crypto::reset();
+        guard
+    }
+
+    // 加锁后清空数据库和图片存储 重新创建最新版本的数据库
+    pub fn reset() -> MutexGuard<'static, ()> {
+        let guard = lock();
+        reset_pool();
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", get_database_path(), suffix));
+        }
+        let _ = fs::remove_dir_all(get_root().join("blob"));
+        let mut client = client().unwrap();
+        client.pragma_update(None, "auto_vacuum", AUTO_VACUUM_INCREMENTAL).unwrap();
+        migration::migrate(&mut client).unwrap();
+        guard
+    }
+}
@@ -2,27 +2,69 @@ use std::borrow::Cow;
 use std::ffi::OsStr;
 use std::os::windows::ffi::OsStrExt;
 use std::ptr::{null, null_mut};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use anyhow::{bail, Result};
 use arboard::{Clipboard, ImageData};
 use image::EncodableLayout;
+use once_cell::sync::Lazy;
 use rusqlite::named_params;
 use winapi::shared::windef::HWND;
 use winapi::um::winuser::{AddClipboardFormatListener, CreateWindowExW, GetMessageW, HWND_MESSAGE, MSG, WM_CLIPBOARDUPDATE};
+use crate::client::blob;
 use crate::client::sqlite::client;
+use crate::settings;
+
+// 再次复制后剪切板监听收到同一张图片的最长时间 超过后作为新的复制保存
+const RE_COPIED_TTL: Duration = Duration::from_secs(5);
+
+// 再次复制的图片的sum和时间 剪切板监听收到这张图片时不再保存
+static RE_COPIED: Lazy<Mutex<Option<(String, Instant)>>> = Lazy::new(|| {
+    Mutex::new(None)
+});
+
+// 是否为刚刚再次复制的图片 只会匹配一次
+pub fn take_re_copied(sum: &str) -> bool {
+    let mut re_copied = RE_COPIED.lock().unwrap();
+    let matched = match re_copied.as_ref() {
+        // 过期后不再匹配
+        Some((_, time)) if time.elapsed() > RE_COPIED_TTL => {
+            *re_copied = None;
+            return false;
+        }
+        Some((re_copied, _)) => re_copied == sum,
+        None => false,
+    };
+    if matched {
+        *re_copied = None;
+    }
+    matched
+}
+
+// 记录一次使用 开启设置时同时移到最前
+fn record_use(image_id: i32) -> Result<()> {
+    let now = chrono::Local::now().timestamp_millis();
+    let to_top = settings::get_settings().re_copy_to_top.unwrap_or(false);
+    client()?.execute(r#"UPDATE image SET use_count = use_count + 1, last_used_at = ?2,
+                      mtime = CASE WHEN ?3 THEN ?2 ELSE mtime END WHERE id = ?1"#, (&image_id, &now, &to_top))?;
+    Ok(())
+}
 
 pub fn re_copy(image_id: i32) -> Result<()> {
-    let client = client();
-    let mut stmt = client.prepare("SELECT image FROM image WHERE id = :image_id")?;
+    let client = client()?;
+    let mut stmt = client.prepare_cached("SELECT sum FROM image WHERE id = :image_id")?;
     let mut rows = stmt.query(named_params! {
         ":image_id": image_id,
     })?;
-    let mut image: Vec<u8> = vec![];
+    let mut sum: Option<String> = None;
     while let Some(row) = rows.next()? {
-        image = row.get(0)?;
-    }
-    if image.len() == 0 {
-        bail!("no such image id: {}", image_id);
+        sum = row.get(0)?;
     }
+    let sum = match sum {
+        Some(sum) => sum,
+        None => bail!("no such image id: {}", image_id),
+    };
+    let image = blob::get(&sum)?;
     let image = image::load_from_memory(image.as_slice())?;
     let image = image.into_rgba8();
     let image = ImageData {
@@ -32,6 +74,9 @@ pub fn re_copy(image_id: i32) -> Result<()> {
     };
     let mut clipboard = Clipboard::new().unwrap();
     clipboard.set_image(image)?;
+    // 写入剪切板成功后才记录 失败时不影响之后真正复制同一张图片
+    *RE_COPIED.lock().unwrap() = Some((sum, Instant::now()));
+    record_use(image_id)?;
     Ok(())
 }
 
@@ -1,11 +1,14 @@
-use std::{env, fs};
+use std::{env, fs, process};
 use std::ops::Deref;
 use std::path::Path;
 use once_cell::sync::Lazy;
 
 static ROOT: Lazy<Box<Path>> = Lazy::new(|| {
     let mut root;
-    if cfg!(debug_assertions) {
+    if cfg!(test) {
+        // 测试使用每个进程独立的临时目录 不影响开发时的数据
+        root = env::temp_dir().join(format!("windows-clipboard-image-helper-test-{}", process::id()));
+    } else if cfg!(debug_assertions) {
         root = env::current_dir().unwrap();
     } else {
         root = env::current_exe().unwrap();
@@ -13,11 +16,20 @@ static ROOT: Lazy<Box<Path>> = Lazy::new(|| {
     }
     let root = root.join(".windows-clipboard-image-helper").into_boxed_path();
     if !root.is_dir() {
-        fs::create_dir(root.clone()).unwrap();
+        fs::create_dir_all(root.clone()).unwrap();
     }
     root
 });
 
 pub fn get_root() -> &'static Path {
     ROOT.deref()
-}
\ No newline at end of file
+}
+
+// 根据解码后的RGBA像素计算图片的sum 不受PNG编码器差异的影响
+pub fn pixel_sum(width: u32, height: u32, rgba: &[u8]) -> String {
+    let mut data = Vec::with_capacity(8 + rgba.len());
+    data.extend_from_slice(&width.to_le_bytes());
+    data.extend_from_slice(&height.to_le_bytes());
+    data.extend_from_slice(rgba);
+    sha256::digest(data.as_slice())
+}
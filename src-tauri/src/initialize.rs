@@ -3,7 +3,7 @@ use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use log::{info, LevelFilter};
-use rusqlite::{Connection, named_params};
+use crate::client::{blob, crypto, migration};
 use crate::client::sqlite::client;
 use crate::common::get_root;
 
@@ -26,36 +26,22 @@ pub fn init_logger() -> Result<log4rs::Handle> {
     Ok(handle)
 }
 
-#[allow(dead_code)]
-fn add_column_if_not_exist(client: &Connection, table: &str, column: &str, kind: &str) -> Result<()> {
-    let f = || -> Result<()> {
-        let mut stmt = client.prepare(format!("SELECT {} FROM {} LIMIT 1", column, table).as_str())?;
-        let mut rows = stmt.query(named_params! {})?;
-        while let Some(_) = rows.next()? {}
-        Ok(())
-    };
-    let r = f();
-    if r.is_err() {
-        client.execute(format!(r"ALTER TABLE {} ADD COLUMN {} {}", table, column, kind).as_str(), ())?;
-    }
-    Ok(())
-}
+// PRAGMA auto_vacuum的INCREMENTAL模式
+pub const AUTO_VACUUM_INCREMENTAL: i64 = 2;
 
 pub fn init_database() -> Result<()> {
-    let client = client();
-    client.execute(r#"
-    CREATE TABLE IF NOT EXISTS image (
-        id INTEGER PRIMARY KEY AUTOINCREMENT,
-        image BLOB,
-        ocr TEXT,
-        size INTEGER,
-        width INTEGER,
-        height INTEGER,
-        ctime INTEGER,
-        mtime INTEGER,
-        sum VARCHAR(64)
-    );"#, ())?;
-    client.execute(r"CREATE INDEX IF NOT EXISTS index_mtime ON image (mtime)", ())?;
-    client.execute(r"CREATE INDEX IF NOT EXISTS index_sum ON image (sum)", ())?;
+    let mut client = client()?;
+    let version = migration::get_version(&client)?;
+    migration::migrate(&mut client)?;
+    crypto::load(&client)?;
+    // 图片移出数据库后需要VACUUM才能真正缩小数据库文件
+    // 开启增量VACUUM后同样需要完整VACUUM一次才能生效
+    let auto_vacuum: i64 = client.pragma_query_value(None, "auto_vacuum", |row| row.get(0))?;
+    if version < 2 || auto_vacuum != AUTO_VACUUM_INCREMENTAL {
+        client.pragma_update(None, "auto_vacuum", AUTO_VACUUM_INCREMENTAL)?;
+        client.execute_batch("VACUUM")?;
+    }
+    // 清理上次运行中途失败留下的图片
+    blob::gc(&client)?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -23,6 +23,9 @@ async fn main() -> Result<()> {
     clipboard::listen(app::image_insert::save_image);
     tokio::spawn(regular::clean::clean());
     tokio::spawn(regular::ocr::ocr());
+    tokio::spawn(regular::backfill::backfill());
+    tokio::spawn(regular::backup::backup());
+    tokio::spawn(regular::maintenance::maintenance());
 
     app::run().await?;
     Ok(())
@@ -17,12 +17,39 @@ pub enum OCRData {
     Text(String),
 }
 
+// 由OCR生成的默认标题的最大长度
+pub const TITLE_MAX_CHARS: usize = 64;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OCR {
     pub code: i32,
     pub data: OCRData,
 }
 
+impl OCR {
+    // 识别成功时返回全部文本 每个文本框一行
+    pub fn text(&self) -> String {
+        match &self.data {
+            OCRData::Box(boxes) if self.code == 100 => {
+                let text: Vec<&str> = boxes.iter().map(|b| b.text.as_str()).collect();
+                text.join("\n")
+            }
+            _ => "".to_string(),
+        }
+    }
+
+    // 默认标题 取第一行文本
+    pub fn title(&self) -> Option<String> {
+        match &self.data {
+            OCRData::Box(boxes) if self.code == 100 => {
+                let title: String = boxes.first()?.text.trim().chars().take(TITLE_MAX_CHARS).collect();
+                if title.is_empty() { None } else { Some(title) }
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum ImageData {
@@ -53,10 +80,34 @@ impl ImageData {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionStatus {
+    pub enabled: bool,
+    // 开启加密时是否已经输入口令解锁
+    pub unlocked: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    // 图集中不在回收站中的图片数量
+    pub count: i64,
+    pub ctime: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Image {
     pub id: i64,
-    pub image: ImageData,
+    // 只请求缩略图时为空 需要原图时通过ID单独获取
+    pub image: Option<ImageData>,
+    pub thumbnail: Option<ImageData>,
     pub ocr: Option<OCR>,
     pub size: i64,
     pub width: i32,
@@ -64,4 +115,139 @@ pub struct Image {
     pub ctime: i64,
     pub mtime: i64,
     pub sum: String,
-}
\ No newline at end of file
+    // 图片被复制或上传的次数
+    pub occurrence: i64,
+    // 按相似图片搜索时与目标图片感知哈希的汉明距离
+    pub distance: Option<i64>,
+    // 按文本搜索时命中的OCR文本、标题或备注片段 命中部分用<mark>标记
+    pub snippet: Option<String>,
+    // 置顶收藏 不会被自动清理
+    pub pinned: bool,
+    pub tags: Vec<Tag>,
+    // 标题 没有手动设置时取OCR的第一行文本
+    pub title: Option<String>,
+    pub note: Option<String>,
+    // 移入回收站的时间
+    pub deleted_at: Option<i64>,
+    // 从历史中再次复制的次数和最近一次的时间
+    pub use_count: i64,
+    pub last_used_at: Option<i64>,
+    // 按颜色搜索时每个颜色条件在图片中的覆盖比例 顺序与请求中的颜色条件一致
+    pub color_coverage: Option<Vec<f64>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageOccurrence {
+    pub ctime: i64,
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportResult {
+    // 新增的图片数量
+    pub inserted: usize,
+    // 与已有图片合并的数量
+    pub merged: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backup {
+    // 备份目录名 同时是备份的唯一标识
+    pub name: String,
+    pub ctime: i64,
+    // 数据库快照的字节数
+    pub size: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryReport {
+    // 复制到新数据库的行数
+    pub rows: i64,
+    // 无法读取的行数
+    pub lost: i64,
+    // 损坏的数据库保留的位置
+    pub damaged_path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceReport {
+    pub ctime: i64,
+    // 完整性检查没有发现问题
+    pub ok: bool,
+    // integrity_check的输出 没有问题时为ok
+    pub integrity: Vec<String>,
+    // 增量清理释放的页面数
+    pub freed_pages: i64,
+    // 耗时 毫秒
+    pub duration: i64,
+    // 完整性检查失败时的恢复结果
+    pub recovery: Option<RecoveryReport>,
+}
+
+// 触发删除的保留策略
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CleanReason {
+    Age,
+    Daily,
+    Count,
+    Size,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CleanCandidate {
+    pub id: i64,
+    pub size: i64,
+    pub ctime: i64,
+    pub mtime: i64,
+    pub reason: CleanReason,
+    // 彻底删除 否则移入回收站
+    pub purge: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatisticsBucket {
+    // 日期、月份或大小区间
+    pub key: String,
+    pub count: i64,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LargestImage {
+    pub id: i64,
+    pub size: i64,
+    pub width: i32,
+    pub height: i32,
+    pub ctime: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Statistics {
+    // 回收站之外的图片
+    pub count: i64,
+    pub bytes: i64,
+    pub trash_count: i64,
+    pub trash_bytes: i64,
+    pub daily: Vec<StatisticsBucket>,
+    pub monthly: Vec<StatisticsBucket>,
+    pub histogram: Vec<StatisticsBucket>,
+    pub largest: Vec<LargestImage>,
+    pub ocr_done: i64,
+    pub ocr_pending: i64,
+    pub ocr_failed: i64,
+    // 重复复制的次数 以及去重节省的字节数
+    pub duplicate_count: i64,
+    pub duplicate_bytes: i64,
+    // 数据库文件大小 包括WAL
+    pub database_file_bytes: i64,
+    // 数据库中正在使用的页面
+    pub database_live_bytes: i64,
+    pub blob_bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImagePage {
+    pub images: Vec<Image>,
+    // 传入下一次请求获取下一页 没有更多图片时为空
+    pub cursor: Option<String>,
+}
@@ -1,2 +1,13 @@
+use once_cell::sync::Lazy;
+use tokio::sync::RwLock;
+
+pub mod backfill;
+pub mod backup;
 pub mod clean;
-pub mod ocr;
\ No newline at end of file
+pub mod maintenance;
+pub mod ocr;
+
+// 后台任务和剪切板写入期间持有读锁 恢复备份时持有写锁使它们暂停
+pub static PAUSE: Lazy<RwLock<()>> = Lazy::new(|| {
+    RwLock::new(())
+});
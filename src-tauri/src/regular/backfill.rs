@@ -0,0 +1,100 @@
+use anyhow::Result;
+use log::{error, info};
+use rusqlite::named_params;
+use crate::analyzer::{palette, phash};
+use crate::analyzer::thumbnail::make_thumbnail;
+use crate::client::{blob, crypto};
+use crate::client::sqlite::client;
+use crate::regular::PAUSE;
+
+// 每次从数据库中取出的图片数量
+const BATCH: i64 = 16;
+
+// 对满足条件的图片逐个补充计算 单个图片失败只记录日志 不影响其他图片
+fn backfill_each<F: Fn(i64, &[u8]) -> Result<()>>(name: &str, condition: &str, f: F) -> Result<()> {
+    let sql = format!("SELECT id, sum FROM image WHERE id > :last_id AND ({}) ORDER BY id LIMIT :limit", condition);
+    let mut last_id: i64 = 0;
+    let mut count = 0;
+    loop {
+        let mut row_list: Vec<(i64, String)> = vec![];
+        {
+            let client = client()?;
+            let mut stmt = client.prepare_cached(sql.as_str())?;
+            let mut rows = stmt.query(named_params! {
+                ":last_id": last_id,
+                ":limit": BATCH,
+            })?;
+            while let Some(row) = rows.next()? {
+                row_list.push((row.get(0)?, row.get(1)?));
+            }
+        }
+        if row_list.is_empty() {
+            break;
+        }
+        for (id, sum) in &row_list {
+            last_id = *id;
+            match blob::get(sum).and_then(|data| f(*id, data.as_slice())) {
+                Ok(_) => count += 1,
+                Err(err) => error!("backfill {} of image {} with error: {}", name, id, err.to_string()),
+            }
+        }
+    }
+    if count > 0 {
+        info!("backfill {} for {} images", name, count);
+    }
+    Ok(())
+}
+
+fn backfill_phash() -> Result<()> {
+    backfill_each("phash", "phash IS NULL", |id, data| {
+        let image = image::load_from_memory(data)?.into_rgba8();
+        let client = client()?;
+        client.execute("UPDATE image SET phash = ?2 WHERE id = ?1", (&id, &phash::dhash(&image)))?;
+        Ok(())
+    })
+}
+
+fn backfill_thumbnail() -> Result<()> {
+    backfill_each("thumbnail", "thumbnail IS NULL", |id, data| {
+        let image = image::load_from_memory(data)?.into_rgba8();
+        let client = client()?;
+        let thumbnail = crypto::seal(make_thumbnail(&image)?.as_slice())?;
+        client.execute("UPDATE image SET thumbnail = ?2 WHERE id = ?1", (&id, &thumbnail))?;
+        Ok(())
+    })
+}
+
+fn backfill_palette() -> Result<()> {
+    backfill_each("palette", "palette IS NULL", |id, data| {
+        let image = image::load_from_memory(data)?.into_rgba8();
+        let client = client()?;
+        client.execute("UPDATE image SET palette = ?2 WHERE id = ?1", (&id, &palette::make_palette(&image)))?;
+        Ok(())
+    })
+}
+
+fn backfill_all() -> Result<()> {
+    // 加密的历史在解锁前无法读取图片
+    if crypto::is_locked() {
+        return Ok(());
+    }
+    backfill_phash()?;
+    backfill_thumbnail()?;
+    backfill_palette()?;
+    Ok(())
+}
+
+pub async fn backfill() {
+    loop {
+        // 解码图片比较耗时 放到阻塞线程中执行
+        let pause = PAUSE.read().await;
+        match tokio::task::spawn_blocking(backfill_all).await {
+            Ok(Err(err)) => error!("regular backfill with error: {}", err.to_string()),
+            Err(err) => error!("regular backfill with error: {}", err.to_string()),
+            _ => {}
+        }
+        drop(pause);
+        // 每60秒检查一次有没有需要补充计算的图片
+        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+    }
+}
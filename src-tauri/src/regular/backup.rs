@@ -0,0 +1,38 @@
+use log::error;
+use crate::client::backup;
+use crate::regular::PAUSE;
+use crate::settings::get_settings;
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+fn backup_inner(interval_hours: i64, retention: i64) -> anyhow::Result<()> {
+    let now = chrono::Local::now().timestamp_millis();
+    let due = match backup::latest_ctime()? {
+        Some(ctime) => now - ctime >= interval_hours * HOUR_MILLIS,
+        None => true,
+    };
+    if due {
+        backup::create()?;
+        backup::rotate(retention.max(1) as usize)?;
+    }
+    Ok(())
+}
+
+pub async fn backup() {
+    loop {
+        let settings = get_settings();
+        let interval_hours = settings.backup_interval_hours.or(Some(24)).unwrap();
+        // 间隔为0时不自动备份
+        if interval_hours > 0 {
+            let _pause = PAUSE.read().await;
+            let retention = settings.backup_retention.or(Some(7)).unwrap();
+            match tokio::task::spawn_blocking(move || backup_inner(interval_hours, retention)).await {
+                Ok(Err(err)) => error!("regular backup with error: {}", err.to_string()),
+                Err(err) => error!("regular backup with error: {}", err.to_string()),
+                _ => {}
+            }
+        }
+        // 每10分钟检查一次是否需要备份
+        tokio::time::sleep(std::time::Duration::from_secs(600)).await;
+    }
+}
@@ -1,50 +1,29 @@
-use std::fs;
-use anyhow::Result;
-use log::error;
-use rusqlite::named_params;
-use crate::client::sqlite::{client, get_database_path};
-use crate::settings::{get_settings, DatabaseLimitType};
-
-pub fn get_count_of_image() -> Result<i64> {
-    let client = client();
-    let mut stmt = client.prepare("SELECT count(*) FROM image")?;
-    let mut rows = stmt.query(named_params! {})?;
-    let mut count = 0;
-    while let Some(row) = rows.next()? {
-        count = row.get(0)?;
-    }
-    Ok(count)
-}
+use log::{error, info};
+use crate::app::{image_clean, image_trash};
+use crate::app::image_clean::RetentionPolicy;
+use crate::regular::PAUSE;
+use crate::settings::get_settings;
 
 pub async fn clean() {
     loop {
+        let pause = PAUSE.read().await;
         let settings = get_settings();
-        let mut need_clean = false;
-        let database_limit_type = &settings.database_limit_type.or(Some(DatabaseLimitType::MB)).unwrap();
-        match database_limit_type {
-            DatabaseLimitType::MB => {
-                let result = fs::metadata(get_database_path());
-                match result {
-                    Ok(data) => need_clean = (data.len() / 1024 / 1024) as i64 > settings.database_limit.or(Some(1024)).unwrap(),
-                    Err(err) => error!("regular cleaning error: {}", err.to_string()),
-                }
-            }
-            DatabaseLimitType::NUM => {
-                let count = get_count_of_image();
-                match count {
-                    Ok(count) => need_clean = count > settings.database_limit.or(Some(1024)).unwrap(),
-                    Err(err) => error!("regular cleaning error: {}", err.to_string()),
-                }
-            }
+        // 彻底删除在回收站中过期的图片
+        if let Err(err) = image_trash::purge_expired(settings.trash_retention_days.or(Some(30)).unwrap()) {
+            error!("regular cleaning error: {}", err.to_string());
         }
-        if need_clean {
-            let client = client();
-            const SQL: &str = r#"DELETE FROM image WHERE id IN (SELECT id FROM image ORDER BY mtime LIMIT 1)"#;
-            if let Err(err) = client.execute(SQL, ()) {
-                error!("regular cleaning error: {}", err.to_string());
-            }
+        // 一次删除所有超出保留策略的图片
+        let policy = RetentionPolicy::from_settings(&settings);
+        let result = tokio::task::spawn_blocking(move || {
+            image_clean::apply(&image_clean::plan(&policy)?)
+        }).await.map_err(anyhow::Error::from).and_then(|result| result);
+        match result {
+            Ok(0) => {}
+            Ok(count) => info!("regular cleaning: {} images removed by retention policy", count),
+            Err(err) => error!("regular cleaning error: {}", err.to_string()),
         }
-        // 每20秒检查一次 超了只删一个
+        drop(pause);
+        // 每20秒检查一次
         tokio::time::sleep(std::time::Duration::from_secs(20)).await;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,173 @@
+use std::fs;
+use std::sync::RwLock;
+use anyhow::Result;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use rusqlite::{Connection, named_params};
+use crate::client::{backup, blob, fts, migration};
+use crate::client::sqlite::{client, get_database_path, reset_pool};
+use crate::common::get_root;
+use crate::initialize::AUTO_VACUUM_INCREMENTAL;
+use crate::model::{MaintenanceReport, RecoveryReport};
+use crate::regular::PAUSE;
+
+// 恢复时按依赖顺序复制的表 被引用的表在前
+const TABLES: [&str; 8] = ["image", "image_occurrence", "tag", "image_tag", "collection", "collection_image", "encryption", "encryption_key"];
+
+// 最近一次维护的结果
+static REPORT: Lazy<RwLock<Option<MaintenanceReport>>> = Lazy::new(|| {
+    RwLock::new(None)
+});
+
+pub fn get_report() -> Option<MaintenanceReport> {
+    REPORT.read().unwrap().clone()
+}
+
+fn freelist_count(client: &Connection) -> Result<i64> {
+    Ok(client.pragma_query_value(None, "freelist_count", |row| row.get(0))?)
+}
+
+fn integrity_check(client: &Connection) -> Result<Vec<String>> {
+    let mut stmt = client.prepare("PRAGMA integrity_check")?;
+    let mut rows = stmt.query(named_params! {})?;
+    let mut ret = vec![];
+    while let Some(row) = rows.next()? {
+        ret.push(row.get(0)?);
+    }
+    Ok(ret)
+}
+
+// 检查数据库完整性 没有问题时优化查询计划并回收空闲页
+fn check() -> Result<MaintenanceReport> {
+    let start = std::time::Instant::now();
+    let client = client()?;
+    // 损坏严重时检查本身会失败 同样需要恢复
+    let integrity = match integrity_check(&client) {
+        Ok(integrity) => integrity,
+        Err(err) => vec![err.to_string()],
+    };
+    let ok = integrity.len() == 1 && integrity[0] == "ok";
+    let mut freed_pages = 0;
+    if ok {
+        client.execute_batch("PRAGMA optimize")?;
+        let before = freelist_count(&client)?;
+        client.execute_batch("PRAGMA incremental_vacuum")?;
+        freed_pages = before - freelist_count(&client)?;
+        client.query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |_| Ok(()))?;
+    }
+    Ok(MaintenanceReport {
+        ctime: chrono::Local::now().timestamp_millis(),
+        ok,
+        integrity,
+        freed_pages,
+        duration: start.elapsed().as_millis() as i64,
+        recovery: None,
+    })
+}
+
+// 复制一张表中可以读取的行 返回复制和丢失的行数
+fn recover_table(fresh: &Connection, table: &str) -> Result<(i64, i64)> {
+    let columns = {
+        let mut stmt = fresh.prepare("SELECT name FROM pragma_table_info(?1)")?;
+        let columns = stmt.query_map((table,), |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+        columns.join(", ")
+    };
+    let copy = format!("INSERT OR IGNORE INTO main.{0} ({1}) SELECT {1} FROM damaged.{0}", table, columns);
+    match fresh.execute(copy.as_str(), ()) {
+        Ok(count) => return Ok((count as i64, 0)),
+        Err(err) => warn!("recover table {} with error: {}, fallback to row by row", table, err.to_string()),
+    }
+    // 整表复制失败 逐行复制 跳过读取失败的行
+    fresh.execute(format!("DELETE FROM main.{}", table).as_str(), ())?;
+    let max_rowid: Option<i64> = fresh.query_row(format!("SELECT MAX(rowid) FROM damaged.{}", table).as_str(), (), |row| row.get(0))?;
+    let copy = format!("{} WHERE rowid = ?1", copy);
+    let (mut count, mut lost) = (0, 0);
+    for rowid in 1..=max_rowid.unwrap_or(0) {
+        match fresh.execute(copy.as_str(), (&rowid,)) {
+            Ok(n) => count += n as i64,
+            Err(_) => lost += 1,
+        }
+    }
+    Ok((count, lost))
+}
+
+// 把损坏的数据库中可以读取的行复制到新的数据库 再用新的数据库替换当前数据库
+fn recover() -> Result<RecoveryReport> {
+    let now = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let fresh_path = get_root().join("database.recover.sqlite3");
+    if fresh_path.exists() {
+        fs::remove_file(fresh_path.as_path())?;
+    }
+    let mut fresh = Connection::open(fresh_path.as_path())?;
+    fresh.pragma_update(None, "auto_vacuum", AUTO_VACUUM_INCREMENTAL)?;
+    migration::migrate(&mut fresh)?;
+    fresh.execute("ATTACH DATABASE ?1 AS damaged", (get_database_path(),))?;
+    let (mut rows, mut lost) = (0, 0);
+    for table in TABLES {
+        match recover_table(&fresh, table) {
+            Ok((count, lost_count)) => {
+                rows += count;
+                lost += lost_count;
+            }
+            // 整张表无法读取
+            Err(err) => error!("recover table {} with error: {}", table, err.to_string()),
+        }
+    }
+    fresh.execute_batch(r#"
+    DETACH DATABASE damaged;
+    DELETE FROM image_occurrence WHERE image_id NOT IN (SELECT id FROM image);
+    DELETE FROM image_tag WHERE image_id NOT IN (SELECT id FROM image) OR tag_id NOT IN (SELECT id FROM tag);
+    DELETE FROM collection_image WHERE image_id NOT IN (SELECT id FROM image) OR collection_id NOT IN (SELECT id FROM collection);
+    "#)?;
+    // 重建全文索引 开启加密时只删除
+    {
+        let mut stmt = fresh.prepare("SELECT id FROM image")?;
+        let ids = stmt.query_map((), |row| row.get(0))?.collect::<rusqlite::Result<Vec<i64>>>()?;
+        for id in ids {
+            fts::update(&fresh, id)?;
+        }
+    }
+    // 保留损坏的数据库 以便之后手动处理
+    let damaged_path = get_root().join(format!("database.damaged-{}.sqlite3", now));
+    fs::copy(get_database_path(), damaged_path.as_path())?;
+    {
+        let mut client = client()?;
+        backup::copy(&fresh, &mut client)?;
+        blob::gc(&client)?;
+    }
+    reset_pool();
+    drop(fresh);
+    fs::remove_file(fresh_path.as_path())?;
+    info!("database recovered with {} rows, {} rows lost", rows, lost);
+    Ok(RecoveryReport {
+        rows,
+        lost,
+        damaged_path: damaged_path.to_string_lossy().to_string(),
+    })
+}
+
+// 执行一次维护 完整性检查失败时恢复数据库
+pub async fn run() -> Result<MaintenanceReport> {
+    let mut report = {
+        let _pause = PAUSE.read().await;
+        tokio::task::spawn_blocking(check).await??
+    };
+    if !report.ok {
+        error!("database integrity check failed: {}", report.integrity.join("; "));
+        // 恢复期间暂停后台任务和剪切板写入
+        let _pause = PAUSE.write().await;
+        report.recovery = Some(tokio::task::spawn_blocking(recover).await??);
+    }
+    *REPORT.write().unwrap() = Some(report.clone());
+    Ok(report)
+}
+
+pub async fn maintenance() {
+    loop {
+        if let Err(err) = run().await {
+            error!("regular maintenance with error: {}", err.to_string());
+        }
+        // 每天维护一次
+        tokio::time::sleep(std::time::Duration::from_secs(24 * 60 * 60)).await;
+    }
+}
@@ -1,70 +1,101 @@
-use std::ops::Deref;
+use std::{fs, io};
 use std::path::PathBuf;
 use anyhow::Result;
 use log::error;
-use once_cell::sync::Lazy;
-use rusqlite::named_params;
-use tokio::sync::Mutex;
+use rusqlite::{named_params, TransactionBehavior};
 use crate::analyzer::ocr::{analyze, status};
+use crate::client::{blob, crypto, fts};
 use crate::client::sqlite::client;
 use crate::common::get_root;
 use crate::model::OCR;
+use crate::regular::PAUSE;
 use crate::settings;
 
-static LOCK: Lazy<Mutex<()>> = Lazy::new(|| {
-    Mutex::new(())
-});
-
-static CACHE_PATH: Lazy<PathBuf> = Lazy::new(|| {
-    let root = get_root();
-    root.join("cache.2.png")
-});
-
-fn get_one_without_ocr() -> Result<(i32, Vec<u8>)> {
-    let c = client();
+fn get_one_without_ocr() -> Result<(i32, String)> {
+    let c = client()?;
     let mut id = -1;
-    let mut image: Vec<u8> = vec![];
-    let mut stmt = c.prepare("SELECT id, image FROM image WHERE ocr IS NULL LIMIT 1")?;
+    let mut sum = "".to_string();
+    let mut stmt = c.prepare_cached("SELECT id, sum FROM image WHERE ocr IS NULL LIMIT 1")?;
     let mut rows = stmt.query(named_params! {})?;
     while let Some(row) = rows.next()? {
         id = row.get(0)?;
-        image = row.get(1)?;
+        sum = row.get(1)?;
     }
-    return Ok((id, image));
+    return Ok((id, sum));
 }
 
 fn update_ocr(id: &i32, ocr: &OCR) -> Result<()> {
-    let c = client();
-    c.execute("UPDATE image SET ocr = ?2 WHERE id = ?1", (&id, &serde_json::to_string(&ocr)?))?;
+    let text = crypto::seal_text(Some(serde_json::to_string(&ocr)?.as_str()))?;
+    let title = crypto::seal_text(ocr.title().as_deref())?;
+    let mut c = client()?;
+    let tx = c.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    tx.execute("UPDATE image SET ocr = ?2 WHERE id = ?1", (&id, &text))?;
+    // 没有标题时使用OCR的第一行文本作为默认标题
+    tx.execute("UPDATE image SET title = ?2 WHERE id = ?1 AND title IS NULL", (&id, &title))?;
+    // 同步更新全文索引
+    fts::update(&tx, *id as i64)?;
+    tx.commit()?;
     Ok(())
 }
 
+fn temp_path() -> PathBuf {
+    get_root().join("cache.ocr.png")
+}
+
+// 解密出的临时文件 离开作用域时删除 识别出错或者panic时也不会留下明文
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(self.0.as_path()) {
+            if err.kind() == io::ErrorKind::NotFound {
+                return;
+            }
+            error!("remove ocr temp file error: {}", err);
+        }
+    }
+}
+
+// 开启加密时存储中的图片无法直接识别 解密到临时文件后再识别
+async fn analyze_blob(sum: &str) -> Result<OCR> {
+    if !crypto::is_enabled() {
+        return analyze(blob::get_path(sum)?.as_path()).await;
+    }
+    let data = blob::get(sum)?;
+    let file = TempFile(temp_path());
+    fs::write(file.0.as_path(), data)?;
+    analyze(file.0.as_path()).await
+}
+
 async fn ocr_inner() -> Result<bool> {
-    // OCR未就绪
-    if status().await? <= 100.0 {
+    // OCR未就绪或者加密的历史还没有解锁
+    if status().await? <= 100.0 || crypto::is_locked() {
         return Ok(false);
     }
-    let (id, image) = get_one_without_ocr()?;
+    let (id, sum) = get_one_without_ocr()?;
     if id == -1 {
         return Ok(false);
     }
-    let r;
-    {
-        let _lock = LOCK.lock().await;
-        std::fs::write(CACHE_PATH.deref(), image.as_slice())?;
-        r = analyze(CACHE_PATH.deref()).await?;
-    }
+    let r = analyze_blob(&sum).await?;
     update_ocr(&id, &r)?;
     Ok(true)
 }
 
 pub async fn ocr() {
+    // 上次运行中途崩溃时可能留下解密的临时文件
+    let path = temp_path();
+    if path.exists() {
+        if let Err(err) = fs::remove_file(path.as_path()) {
+            error!("remove ocr temp file error: {}", err);
+        }
+    }
     loop {
         let settings = settings::get_settings();
         let mut ok = true;
         while ok {
             ok = false;
             if settings.ocr_feature.is_some_and(|x| x) {
+                let _pause = PAUSE.read().await;
                 match ocr_inner().await {
                     Ok(r) => {
                         ok = r;
@@ -78,4 +109,4 @@ pub async fn ocr() {
         // 每10秒检查有没有图片没有进行过OCR并对其进行OCR检查
         tokio::time::sleep(std::time::Duration::from_secs(10)).await;
     }
-}
\ No newline at end of file
+}
@@ -99,12 +99,42 @@ pub enum DatabaseLimitType {
     NUM,
 }
 
+// 超出保留策略时删除图片的顺序 置顶的图片不会被删除
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CleanOrder {
+    // 最早保存的在前
+    Oldest,
+    // 最久没有使用的在前
+    LeastRecentlyUsed,
+    // 最大的在前
+    Largest,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Updater)]
 pub struct Settings {
     pub auto_start: Option<bool>,
+    // 旧版本的存储上限 只在没有设置retention_max_mb和retention_max_count时使用
     pub database_limit_type: Option<DatabaseLimitType>,
     pub database_limit: Option<i64>,
+    // 以下保留策略可以同时生效 为0时不限制
+    // 最近一次使用超过天数的图片
+    pub retention_max_age_days: Option<i64>,
+    // 图片数量上限
+    pub retention_max_count: Option<i64>,
+    // 占用空间上限 包括数据库和图片文件
+    pub retention_max_mb: Option<i64>,
+    // 每天保存的图片数量上限
+    pub retention_daily_limit: Option<i64>,
+    pub clean_order: Option<CleanOrder>,
     pub ocr_feature: Option<bool>,
+    // 回收站中的图片保留的天数 超过后彻底删除
+    pub trash_retention_days: Option<i64>,
+    // 自动备份的间隔小时数 为0时不自动备份
+    pub backup_interval_hours: Option<i64>,
+    // 保留的备份数量
+    pub backup_retention: Option<i64>,
+    // 从历史中再次复制的图片移到最前
+    pub re_copy_to_top: Option<bool>,
 }
 
 fn get_settings_path() -> PathBuf {
@@ -135,7 +165,16 @@ static SETTINGS: Lazy<RwLock<Settings>> = Lazy::new(|| {
             auto_start: Some(false),
             database_limit_type: Some(DatabaseLimitType::MB),
             database_limit: Some(1024),
+            retention_max_age_days: Some(0),
+            retention_max_count: Some(0),
+            retention_max_mb: Some(1024),
+            retention_daily_limit: Some(0),
+            clean_order: Some(CleanOrder::Oldest),
             ocr_feature: Some(false),
+            trash_retention_days: Some(30),
+            backup_interval_hours: Some(24),
+            backup_retention: Some(7),
+            re_copy_to_top: Some(false),
         };
         fs::write(path.as_path(), serde_json::to_string(&settings).unwrap().as_bytes()).unwrap();
         settings
@@ -4,12 +4,21 @@ import {AppstoreOutlined, ArrowLeftOutlined, SettingOutlined} from "@ant-design/
 import Index from "./index";
 import Settings from "./settings";
 import Detail from "./detail";
+import Unlock from "./unlock";
 import {listen, TauriEvent} from "@tauri-apps/api/event";
 import {invoke} from "@tauri-apps/api";
 
 export default function App() {
   const [pageInfo, setPageInfo] = useState<'index' | 'settings' | 'detail' | 'empty'>('index');
   const [imageId, setImageId] = useState<number>(0);
+  const [locked, setLocked] = useState<boolean>(false);
+  useEffect(() => {
+    invoke('encryption_status', {}).then((value: any) => {
+      setLocked(value.enabled && !value.unlocked);
+    }).catch((e) => {
+      console.error(e);
+    });
+  }, []);
   useEffect(() => {
     // 聚焦
     listen(TauriEvent.WINDOW_FOCUS, () => {
@@ -24,6 +33,9 @@ export default function App() {
     });
   }, []);
   const Main = (() => {
+    if (locked) {
+      return <Unlock onUnlock={() => setLocked(false)}/>;
+    }
     if (pageInfo === 'index') {
       return (
         <>
@@ -17,7 +17,7 @@ export default function Detail(props: { imageId: number, jumpIndex: () => void }
     }).then((value) => {
       const v = value as any;
       setTimeout(() => {
-        setImage(v[0]);
+        setImage(v.images[0]);
       }, 0);
     });
   }, []);
@@ -35,7 +35,7 @@ function ImageBlock(props: ImageBlockType) {
   const [hover, setHover] = useState(false);
   const {image, jumpDetailPage, onView, setSelected} = props;
   const {width, height, ctime, mtime} = image;
-  const src = `data:image/png;base64,${image.image}`;
+  const src = `data:image/png;base64,${image.thumbnail}`;
   const blockWidth = 170;
   const blockHeight = 170;
   const style = CalcImagePaddleStyle(blockWidth, blockHeight, width, height);
@@ -82,6 +82,8 @@ export default function Index(props: { jumpDetailPage: (imageId: number) => void
   const [images, setImages] = useState<any[]>([]);
   const [pageNo, setPageNo] = useState(1);
   const [lastImageLen, setLastImageLen] = useState(0);
+  // 下一页的游标 没有更多图片时为空
+  const [cursor, setCursor] = useState<string | undefined>(undefined);
   const [searchText, setSearchText] = useState('');
   const [dateRange, setDateRange] = useState<number[]>([]);
   const [coverRatio, setCoverRatio] = useState<[number, number]>([50, 100]);
@@ -94,9 +96,9 @@ export default function Index(props: { jumpDetailPage: (imageId: number) => void
     showImageSearchText?: string,
     showImageDateRange?: number[],
     showColorFilter?: [number, number, number],
-    mtime?: number,
+    cursor?: string,
   }) => {
-    const {reload, mtime} = props;
+    const {reload, cursor} = props;
     let {showImageSearchText, showImageDateRange, showColorFilter} = props;
     setLoading(true);
     if (reload) {
@@ -108,8 +110,9 @@ export default function Index(props: { jumpDetailPage: (imageId: number) => void
     showColorFilter = showColorFilter ?? colorFilter;
     invoke('get_image', {
       request: {
-        mtime,
+        cursor,
         limit: 16,
+        thumbnail: true,
         text: !!showImageSearchText ? [showImageSearchText] : undefined,
         date_range_from: showImageDateRange && showImageDateRange.length >= 1 ? showImageDateRange[0] * 1000 : undefined,
         date_range_to: showImageDateRange && showImageDateRange.length >= 2 ? showImageDateRange[1] * 1000 : undefined,
@@ -123,8 +126,10 @@ export default function Index(props: { jumpDetailPage: (imageId: number) => void
         } : undefined,
       }
     }).then((value) => {
-      const v = value as any[];
-      setLastImageLen(v.length);
+      const page = value as any;
+      const v = page.images as any[];
+      setCursor(page.cursor ?? undefined);
+      setLastImageLen(page.cursor ? v.length : 0);
       if (reload) {
         setImages(v as any[]);
       } else {
@@ -138,11 +143,7 @@ export default function Index(props: { jumpDetailPage: (imageId: number) => void
   }, []);
   const content = [];
   let index = 0;
-  let mtime: number | undefined = undefined;
   for (const image of images) {
-    if (mtime === undefined || mtime > image.mtime) {
-      mtime = image.mtime;
-    }
     if (index % 4 === 0) {
       content.push(<div style={{marginTop: 20}} key={content.length}/>);
       content.push(<span style={{marginLeft: 15}} key={content.length}/>);
@@ -153,10 +154,10 @@ export default function Index(props: { jumpDetailPage: (imageId: number) => void
     content.push(
       <span key={content.length}>
         <ImageBlock image={image} jumpDetailPage={props.jumpDetailPage} onView={
-          pageNo * 16 - 1 === index ? (inView) => {
+          pageNo * 16 - 1 === index && cursor ? (inView) => {
             if (inView) {
               setPageNo(pageNo + 1);
-              showImage({reload: false, mtime: mtime});
+              showImage({reload: false, cursor});
             }
           } : undefined
         } setSelected={(selected) => {
@@ -1,8 +1,11 @@
 import {
   Button,
   Checkbox,
+  Input,
   InputNumber,
+  List,
   message,
+  Popconfirm,
   Select,
   Skeleton,
   Upload as AntdUpload,
@@ -13,8 +16,18 @@ import {invoke} from "@tauri-apps/api";
 import React, {useEffect, useState} from "react";
 import {open} from "@tauri-apps/api/dialog";
 import {InboxOutlined} from "@ant-design/icons";
+import {DateToString} from "./util";
 
 const {Option} = Select;
+
+type CleanOrder = 'Oldest' | 'LeastRecentlyUsed' | 'Largest';
+
+const CleanReasonText: { [key: string]: string } = {
+  Age: '超过保留天数',
+  Daily: '超过每天保存上限',
+  Count: '超过数量上限',
+  Size: '超过存储空间上限',
+};
 const {Dragger} = AntdUpload;
 
 function Upload() {
@@ -80,15 +93,230 @@ function Upload() {
   )
 }
 
+function Archive() {
+  const [messageApi, contextHolder] = message.useMessage();
+  const [loading, setLoading] = useState(false);
+  // 选择文件时窗口失去焦点 不能刷新页面
+  const run = (select: () => Promise<string | null>, action: (path: string) => Promise<string>) => {
+    invoke('escape_blur', {escape: true}).then(select).finally(() => {
+      invoke('escape_blur', {escape: false}).catch((s: string) => {
+        return messageApi.open({
+          type: 'error',
+          content: s,
+        });
+      });
+    }).then((path) => {
+      if (!path) {
+        return;
+      }
+      setLoading(true);
+      return action(path).then((content) => {
+        return messageApi.open({
+          type: 'success',
+          content,
+        });
+      }).finally(() => {
+        setLoading(false);
+      });
+    }).catch((s: string) => {
+      return messageApi.open({
+        type: 'error',
+        content: s,
+      });
+    });
+  };
+  return (
+    <>
+      {contextHolder}
+      <Button loading={loading} onClick={() => {
+        run(async () => {
+          const dir = await open({directory: true});
+          return typeof dir === 'string' ? dir : null;
+        }, async (dir) => {
+          const path = `${dir}\\clipboard-image-${Date.now()}.zip`;
+          const count = await invoke('export_image', {request: {}, path});
+          return `已导出${count}张图片到${path}`;
+        });
+      }}>导出全部图片</Button>
+      <Button loading={loading} style={{marginLeft: 10}} onClick={() => {
+        run(async () => {
+          const file = await open({filters: [{name: '压缩包', extensions: ['zip']}]});
+          return typeof file === 'string' ? file : null;
+        }, async (path) => {
+          const result: any = await invoke('import_image', {path});
+          return `新增${result.inserted}张图片，合并${result.merged}张图片`;
+        });
+      }}>导入</Button>
+    </>
+  );
+}
+
+function Backups() {
+  const [messageApi, contextHolder] = message.useMessage();
+  const [backups, setBackups] = useState<any[] | undefined>(undefined);
+  const [loading, setLoading] = useState(false);
+  const refresh = () => {
+    invoke('get_backup', {}).then((value: any) => {
+      setBackups(value);
+    }).catch((e) => {
+      console.error(e);
+    });
+  };
+  useEffect(refresh, []);
+  if (backups === undefined) {
+    return <Spin/>;
+  }
+  const error = (s: string) => {
+    return messageApi.open({
+      type: 'error',
+      content: s,
+    });
+  };
+  return (
+    <>
+      {contextHolder}
+      <Button loading={loading} onClick={() => {
+        setLoading(true);
+        invoke('create_backup', {}).then(refresh).catch(error).finally(() => {
+          setLoading(false);
+        });
+      }}>立即备份</Button>
+      <List style={{width: 600}} size="small" dataSource={backups} renderItem={(backup: any) => (
+        <List.Item actions={[
+          <Popconfirm title="恢复备份" description="当前数据库会被替换，恢复前会自动备份当前数据库。" onConfirm={() => {
+            setLoading(true);
+            // 恢复后重新加载 加密的历史需要重新解锁
+            invoke('restore_backup', {name: backup.name}).then(() => {
+              window.location.reload();
+            }).catch(error).finally(() => {
+              setLoading(false);
+            });
+          }}>
+            <Button size="small" loading={loading}>恢复</Button>
+          </Popconfirm>
+        ]}>
+          {DateToString(new Date(backup.ctime))}（{Math.ceil(backup.size / 1024)}KB）
+        </List.Item>
+      )}/>
+    </>
+  );
+}
+
+// 按已保存的保留策略预览将被删除的图片
+function CleanPreview() {
+  const [candidates, setCandidates] = useState<any[] | undefined>(undefined);
+  const [loading, setLoading] = useState(false);
+  return (
+    <>
+      <Button loading={loading} onClick={() => {
+        setLoading(true);
+        invoke('clean_dry_run', {}).then((value: any) => {
+          setCandidates(value);
+        }).catch((e) => {
+          console.error(e);
+        }).finally(() => {
+          setLoading(false);
+        });
+      }}>预览将被删除的图片</Button>
+      {
+        candidates === undefined ? <></> : (
+          <List style={{width: 600}} size="small" dataSource={candidates} locale={{emptyText: '没有需要删除的图片'}}
+                pagination={{pageSize: 10, size: 'small'}} renderItem={(candidate: any) => (
+            <List.Item>
+              {DateToString(new Date(candidate.ctime))}（{Math.ceil(candidate.size / 1024)}KB）
+              {CleanReasonText[candidate.reason]}，{candidate.purge ? '彻底删除' : '移入回收站'}
+            </List.Item>
+          )}/>
+        )
+      }
+    </>
+  );
+}
+
+function Encryption() {
+  const [messageApi, contextHolder] = message.useMessage();
+  const [enabled, setEnabled] = useState<boolean | undefined>(undefined);
+  const [oldPassphrase, setOldPassphrase] = useState('');
+  const [passphrase, setPassphrase] = useState('');
+  const [loading, setLoading] = useState(false);
+  useEffect(() => {
+    invoke('encryption_status', {}).then((value: any) => {
+      setEnabled(value.enabled);
+    }).catch((e) => {
+      console.error(e);
+    });
+  }, []);
+  if (enabled === undefined) {
+    return <Spin/>;
+  }
+  const submit = () => {
+    setLoading(true);
+    // 加密或重新加密全部图片可能比较慢
+    const request = enabled ?
+      invoke('change_passphrase', {old_passphrase: oldPassphrase, new_passphrase: passphrase}) :
+      invoke('enable_encryption', {passphrase});
+    request.then(() => {
+      setEnabled(true);
+      setOldPassphrase('');
+      setPassphrase('');
+      return messageApi.open({
+        type: 'success',
+        content: enabled ? '口令已修改' : '已开启加密',
+      });
+    }).catch((s: string) => {
+      return messageApi.open({
+        type: 'error',
+        content: s,
+      });
+    }).finally(() => {
+      setLoading(false);
+    });
+  };
+  return (
+    <>
+      {contextHolder}
+      <div>状态：{enabled ? <span style={{color: '#00AA00'}}>已加密</span> : <span>未加密</span>}</div>
+      {
+        enabled ? (
+          <>
+            <div style={{marginTop: 10}}/>
+            <Input.Password style={{width: 333}} addonBefore="原口令" value={oldPassphrase} onChange={(e) => {
+              setOldPassphrase(e.target.value);
+            }}/>
+          </>
+        ) : <></>
+      }
+      <div style={{marginTop: 10}}/>
+      <Input.Password style={{width: 333}} addonBefore={enabled ? '新口令' : '口令'} value={passphrase} onChange={(e) => {
+        setPassphrase(e.target.value);
+      }}/>
+      {/* 已有的备份是明文或者旧口令加密的 完成后会全部删除并重新备份 */}
+      <Popconfirm title={enabled ? '修改口令' : '开启加密'}
+                  description="完成后会删除已有的全部备份，并使用新的密钥重新备份。"
+                  disabled={passphrase.length === 0} onConfirm={submit}>
+        <Button style={{marginLeft: 10}} loading={loading} disabled={passphrase.length === 0}>
+          {enabled ? '修改口令' : '开启加密'}
+        </Button>
+      </Popconfirm>
+    </>
+  );
+}
+
 export default function Settings() {
   const [ready, setReady] = useState(false);
   const [autoStart, setAutoStart] = useState<boolean>(false);
-  const [databaseLimitType, setDatabaseLimitType] = useState<'MB' | 'NUM'>('MB');
-  const [databaseLimit, setDatabaseLimit] = useState<number>(1024);
-  const [databaseLimitMbValid, setDatabaseLimitMbValid] = useState<boolean>(true);
+  const [retentionMaxMb, setRetentionMaxMb] = useState<number>(1024);
+  const [retentionMaxCount, setRetentionMaxCount] = useState<number>(0);
+  const [retentionMaxAgeDays, setRetentionMaxAgeDays] = useState<number>(0);
+  const [retentionDailyLimit, setRetentionDailyLimit] = useState<number>(0);
+  const [cleanOrder, setCleanOrder] = useState<CleanOrder>('Oldest');
   const [ocrStatus, setOcrStatus] = useState<number | undefined>(undefined);
   const [ocrDownloading, setOcrDownloading] = useState<boolean>(false);
   const [ocrFeature, setOcrFeature] = useState<boolean>(false);
+  const [reCopyToTop, setReCopyToTop] = useState<boolean>(false);
+  const [trashRetentionDays, setTrashRetentionDays] = useState<number>(30);
+  const [backupIntervalHours, setBackupIntervalHours] = useState<number>(24);
+  const [backupRetention, setBackupRetention] = useState<number>(7);
   const [messageApi, contextHolder] = message.useMessage();
   useEffect(() => {
     invoke('ocr_status', {}).then((value) => {
@@ -107,9 +335,18 @@ export default function Settings() {
   if (!ready) {
     invoke('get_settings', {}).then((value: any) => {
       setAutoStart(value.auto_start);
-      setDatabaseLimitType(value.database_limit_type);
-      setDatabaseLimit(value.database_limit);
+      // 没有设置新的上限时沿用旧版本的存储上限
+      const legacyCount = value.database_limit_type === 'NUM';
+      setRetentionMaxMb(value.retention_max_mb ?? (legacyCount ? 0 : value.database_limit ?? 1024));
+      setRetentionMaxCount(value.retention_max_count ?? (legacyCount ? value.database_limit : 0));
+      setRetentionMaxAgeDays(value.retention_max_age_days ?? 0);
+      setRetentionDailyLimit(value.retention_daily_limit ?? 0);
+      setCleanOrder(value.clean_order ?? 'Oldest');
       setOcrFeature(value.ocr_feature);
+      setReCopyToTop(value.re_copy_to_top ?? false);
+      setTrashRetentionDays(value.trash_retention_days ?? 30);
+      setBackupIntervalHours(value.backup_interval_hours ?? 24);
+      setBackupRetention(value.backup_retention ?? 7);
       setReady(true);
     });
     return <Skeleton style={{marginLeft: 15, marginTop: 15, width: '96%'}}/>;
@@ -179,24 +416,47 @@ export default function Settings() {
       <Checkbox checked={autoStart} onChange={(e) => {
         setAutoStart(e.target.checked);
       }}>开机自启</Checkbox>
+      <Checkbox checked={reCopyToTop} onChange={(e) => {
+        setReCopyToTop(e.target.checked);
+      }}>再次复制的图片移到最前</Checkbox>
       <div style={{marginTop: 10}}/>
-      <InputNumber addonBefore="数据库存储上限" style={{width: 333}} onChange={(e) => {
-        const x = Number(e);
-        if (isNaN(x) || x <= 0) {
-          setDatabaseLimitMbValid(false);
-        } else {
-          setDatabaseLimitMbValid(true);
-          setDatabaseLimit(e as number);
-        }
-      }} addonAfter={(
-        <Select defaultValue={databaseLimitType} style={{width: 100}} onChange={(e) => {
-          setDatabaseLimit(1024);
-          setDatabaseLimitType(e);
-        }}>
-          <Option value="MB">MB</Option>
-          <Option value="NUM">个</Option>
-        </Select>
-      )} defaultValue={databaseLimit} status={databaseLimitMbValid ? '' : 'error'}/>
+      <InputNumber addonBefore="存储空间上限" addonAfter="MB" style={{width: 333}} min={0} precision={0} onChange={(e) => {
+        setRetentionMaxMb((e ?? 0) as number);
+      }} defaultValue={retentionMaxMb}/>
+      <div style={{marginTop: 10}}/>
+      <InputNumber addonBefore="图片数量上限" addonAfter="个" style={{width: 333}} min={0} precision={0} onChange={(e) => {
+        setRetentionMaxCount((e ?? 0) as number);
+      }} defaultValue={retentionMaxCount}/>
+      <div style={{marginTop: 10}}/>
+      <InputNumber addonBefore="图片保留" addonAfter="天" style={{width: 333}} min={0} precision={0} onChange={(e) => {
+        setRetentionMaxAgeDays((e ?? 0) as number);
+      }} defaultValue={retentionMaxAgeDays}/>
+      <div style={{marginTop: 10}}/>
+      <InputNumber addonBefore="每天保存上限" addonAfter="个" style={{width: 333}} min={0} precision={0} onChange={(e) => {
+        setRetentionDailyLimit((e ?? 0) as number);
+      }} defaultValue={retentionDailyLimit}/>
+      <div style={{marginTop: 10}}/>
+      <span>超出上限时优先删除：</span>
+      <Select defaultValue={cleanOrder} style={{width: 150}} onChange={(e) => {
+        setCleanOrder(e);
+      }}>
+        <Option value="Oldest">最早保存的</Option>
+        <Option value="LeastRecentlyUsed">最久没有使用的</Option>
+        <Option value="Largest">最大的</Option>
+      </Select>
+      <div style={{marginTop: 5, color: '#888'}}>为0时不限制</div>
+      <div style={{marginTop: 10}}/>
+      <InputNumber addonBefore="回收站保留" addonAfter="天" style={{width: 333}} min={0} precision={0} onChange={(e) => {
+        setTrashRetentionDays((e ?? 0) as number);
+      }} defaultValue={trashRetentionDays}/>
+      <div style={{marginTop: 10}}/>
+      <InputNumber addonBefore="自动备份间隔" addonAfter="小时" style={{width: 333}} min={0} precision={0} onChange={(e) => {
+        setBackupIntervalHours((e ?? 0) as number);
+      }} defaultValue={backupIntervalHours}/>
+      <div style={{marginTop: 10}}/>
+      <InputNumber addonBefore="保留备份" addonAfter="个" style={{width: 333}} min={1} precision={0} onChange={(e) => {
+        setBackupRetention((e ?? 1) as number);
+      }} defaultValue={backupRetention}/>
       <div style={{marginTop: 15}}/>
       <Checkbox disabled={!ocrStatus || ocrStatus < 100.0} onChange={(e) => {
         setOcrFeature(e.target.checked);
@@ -204,33 +464,46 @@ export default function Settings() {
       <div style={{marginTop: 15}}/>
       {contextHolder}
       <Button type="primary" onClick={() => {
-        if (databaseLimitMbValid) {
-          invoke('set_settings', {
-            settings: {
-              auto_start: autoStart,
-              database_limit_type: databaseLimitType,
-              database_limit: databaseLimit,
-              ocr_feature: ocrFeature,
-            }
+        invoke('set_settings', {
+          settings: {
+            auto_start: autoStart,
+            retention_max_mb: retentionMaxMb,
+            retention_max_count: retentionMaxCount,
+            retention_max_age_days: retentionMaxAgeDays,
+            retention_daily_limit: retentionDailyLimit,
+            clean_order: cleanOrder,
+            ocr_feature: ocrFeature,
+            re_copy_to_top: reCopyToTop,
+            trash_retention_days: trashRetentionDays,
+            backup_interval_hours: backupIntervalHours,
+            backup_retention: backupRetention,
+          }
+        }).then(() => {
+          messageApi.open({
+            type: 'success',
+            content: '保存成功',
           }).then(() => {
-            messageApi.open({
-              type: 'success',
-              content: '保存成功',
-            }).then(() => {
-            });
-          }).catch((msg: string) => {
-            messageApi.open({
-              type: 'error',
-              content: msg,
-            }).then(() => {
-            });
           });
-        }
+        }).catch((msg: string) => {
+          messageApi.open({
+            type: 'error',
+            content: msg,
+          }).then(() => {
+          });
+        });
       }}>确认</Button>
+      <h4>清理</h4>
+      <div><CleanPreview/></div>
       <h4>OCR</h4>
       <div><OCRContent/></div>
+      <h4>备份</h4>
+      <div><Backups/></div>
+      <h4>加密</h4>
+      <div><Encryption/></div>
       <Header text="上传图片"/>
       <Upload/>
+      <Header text="导出和导入"/>
+      <Archive/>
     </div>
   );
 }
\ No newline at end of file
@@ -0,0 +1,35 @@
+import {useState} from "react";
+import {Button, Input, message} from "antd";
+import {invoke} from "@tauri-apps/api";
+
+// 加密的历史在启动后需要输入口令解锁
+export default function Unlock(props: { onUnlock: () => void }) {
+  const [passphrase, setPassphrase] = useState('');
+  const [loading, setLoading] = useState(false);
+  const [messageApi, contextHolder] = message.useMessage();
+  const unlock = () => {
+    setLoading(true);
+    invoke('unlock', {passphrase}).then(() => {
+      props.onUnlock();
+    }).catch((msg: string) => {
+      messageApi.open({
+        type: 'error',
+        content: msg,
+      }).then(() => {
+      });
+    }).finally(() => {
+      setLoading(false);
+    });
+  };
+  return (
+    <div style={{marginLeft: 15}}>
+      {contextHolder}
+      <h3>图片历史已加密</h3>
+      <p>解锁前复制的图片暂存在内存中 解锁后保存 最多保留最近16张</p>
+      <Input.Password style={{width: 333}} addonBefore="口令" value={passphrase} onChange={(e) => {
+        setPassphrase(e.target.value);
+      }} onPressEnter={unlock}/>
+      <Button type="primary" style={{marginLeft: 10}} loading={loading} onClick={unlock}>解锁</Button>
+    </div>
+  );
+}